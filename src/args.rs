@@ -0,0 +1,279 @@
+//! Options for apps started with an exec request, e.g. `ssh -t host weather --intensity 0.8`.
+//! Each app declares its options once, and parsing, validation and `--help` all come from that.
+
+use std::{fmt, str::FromStr};
+
+use crate::report::MAX_MESSAGES;
+use crate::seed::SeedSource;
+
+/// What an option takes.
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    Flag,
+    /// A number between the bounds, inclusive.
+    Float(f64, f64),
+    Int(u64, u64),
+    /// A number, or `daily` for the scene of the day.
+    Seed,
+}
+
+pub struct OptSpec {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub help: &'static str,
+}
+
+pub struct AppSpec {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub opts: &'static [OptSpec],
+}
+
+const FPS: OptSpec = OptSpec {
+    name: "fps",
+    kind: Kind::Float(1.0, 120.0),
+    help: "frames per second to aim for, at most the server's limit",
+};
+const SEED: OptSpec = OptSpec {
+    name: "seed",
+    kind: Kind::Seed,
+    help: "replay the randomness of an earlier session, or `daily` for today's",
+};
+const JSON: OptSpec = OptSpec {
+    name: "json",
+    kind: Kind::Flag,
+    help: "print the report as JSON (without a terminal)",
+};
+const HELP: OptSpec = OptSpec {
+    name: "help",
+    kind: Kind::Flag,
+    help: "show this help",
+};
+
+pub const APPS: &[AppSpec] = &[
+    AppSpec {
+        name: "help",
+        about: "Explains what this server can show.",
+        opts: &[HELP],
+    },
+    AppSpec {
+        name: "virus",
+        about: "Very convincing progress messages.",
+        opts: &[
+            OptSpec {
+                name: "errors",
+                kind: Kind::Flag,
+                help: "let some of the steps fail",
+            },
+            SEED,
+            OptSpec {
+                name: "count",
+                kind: Kind::Int(1, MAX_MESSAGES),
+                help: "how many messages to print (without a terminal, default 10)",
+            },
+            JSON,
+            HELP,
+        ],
+    },
+    AppSpec {
+        name: "weather",
+        about: "Snow drifting over a landscape, shared with everyone watching the same scene.\nWithout a terminal, prints a report and forecast instead.",
+        opts: &[
+            OptSpec {
+                name: "snow",
+                kind: Kind::Flag,
+                help: "snowfall, the default and for now only kind of weather",
+            },
+            OptSpec {
+                name: "intensity",
+                kind: Kind::Float(0.0, 4.0),
+                help: "how hard it snows, 1 is normal",
+            },
+            FPS,
+            SEED,
+            JSON,
+            HELP,
+        ],
+    },
+    AppSpec {
+        name: "gif",
+        about: "Plays the server's animations, scaled to the window.",
+        opts: &[HELP],
+    },
+];
+
+pub fn spec(app: &str) -> Option<&'static AppSpec> {
+    APPS.iter().find(|a| a.name == app)
+}
+
+impl AppSpec {
+    /// The `--help` text, with plain `\n` line ends.
+    pub fn help(&self) -> String {
+        let mut out = format!("usage: ssh [-t] <host> {} [options]\n{}\n\noptions:\n", self.name, self.about);
+        for opt in self.opts {
+            let value = match opt.kind {
+                Kind::Flag => "",
+                Kind::Float(..) => " <number>",
+                Kind::Int(..) => " <integer>",
+                Kind::Seed => " <seed>",
+            };
+            let left = format!("--{}{value}", opt.name);
+            out.push_str(&format!("  {left:<22}{}", opt.help));
+            match opt.kind {
+                Kind::Float(lo, hi) => out.push_str(&format!(", {lo} to {hi}")),
+                Kind::Int(lo, hi) => out.push_str(&format!(", {lo} to {hi}")),
+                _ => {}
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Flag,
+    Float(f64),
+    Int(u64),
+}
+
+/// Parsed options of one app.
+#[derive(Clone, Default, Debug)]
+pub struct Args {
+    values: Vec<(&'static str, Value)>,
+}
+impl Args {
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.iter().any(|v| v.0 == name)
+    }
+    pub fn float(&self, name: &str) -> Option<f64> {
+        self.values.iter().rev().find_map(|v| match v {
+            (n, Value::Float(f)) if *n == name => Some(*f),
+            _ => None,
+        })
+    }
+    pub fn int(&self, name: &str) -> Option<u64> {
+        self.values.iter().rev().find_map(|v| match v {
+            (n, Value::Int(i)) if *n == name => Some(*i),
+            _ => None,
+        })
+    }
+    /// Sets `name` as if it had been given, for values decided on the app's behalf.
+    pub fn set_int(&mut self, name: &'static str, value: u64) {
+        self.values.push((name, Value::Int(value)));
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ArgsError {
+    Unknown(String),
+    MissingValue(&'static str),
+    /// Not a number, or out of range.
+    BadValue { opt: &'static str, value: String },
+    /// Not an option; apps take no positional arguments.
+    Unexpected(String),
+    UnexpectedValue(&'static str),
+}
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::Unknown(opt) => write!(f, "unknown option \"{opt}\""),
+            ArgsError::MissingValue(opt) => write!(f, "--{opt} needs a value"),
+            ArgsError::BadValue { opt, value } => write!(f, "\"{value}\" is not a valid value for --{opt}"),
+            ArgsError::Unexpected(arg) => write!(f, "unexpected argument \"{arg}\""),
+            ArgsError::UnexpectedValue(opt) => write!(f, "--{opt} doesn't take a value"),
+        }
+    }
+}
+impl std::error::Error for ArgsError {}
+
+/// Parses `words` (the command's words after the app name) against `spec`. Values can follow
+/// their option as the next word or after `=`.
+pub fn parse(spec: &AppSpec, words: &[String]) -> Result<Args, ArgsError> {
+    let mut args = Args::default();
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        let Some(opt) = word.strip_prefix("--") else {
+            return Err(ArgsError::Unexpected(word.clone()));
+        };
+        let (name, inline) = match opt.split_once('=') {
+            Some((n, v)) => (n, Some(v.to_string())),
+            None => (opt, None),
+        };
+        let opt = spec
+            .opts
+            .iter()
+            .find(|o| o.name == name)
+            .ok_or_else(|| ArgsError::Unknown(word.clone()))?;
+        let value = match opt.kind {
+            Kind::Flag if inline.is_some() => return Err(ArgsError::UnexpectedValue(opt.name)),
+            Kind::Flag => Value::Flag,
+            Kind::Float(lo, hi) => Value::Float(value(opt.name, inline, &mut words, lo, hi)?),
+            Kind::Int(lo, hi) => Value::Int(value(opt.name, inline, &mut words, lo, hi)?),
+            Kind::Seed => {
+                let value = inline.or_else(|| words.next().cloned()).ok_or(ArgsError::MissingValue(opt.name))?;
+                match value.parse::<SeedSource>().ok().and_then(SeedSource::seed) {
+                    Some(seed) => Value::Int(seed),
+                    None => return Err(ArgsError::BadValue { opt: opt.name, value }),
+                }
+            }
+        };
+        args.values.push((opt.name, value));
+    }
+    Ok(args)
+}
+fn value<'a, T: FromStr + PartialOrd>(
+    opt: &'static str,
+    inline: Option<String>,
+    words: &mut impl Iterator<Item = &'a String>,
+    lo: T,
+    hi: T,
+) -> Result<T, ArgsError> {
+    let value = inline.or_else(|| words.next().cloned()).ok_or(ArgsError::MissingValue(opt))?;
+    value
+        .parse()
+        .ok()
+        .filter(|v| *v >= lo && *v <= hi)
+        .ok_or(ArgsError::BadValue { opt, value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_words(app: &str, words: &[&str]) -> Result<Args, ArgsError> {
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        parse(spec(app).unwrap(), &words)
+    }
+
+    #[test]
+    fn values() {
+        let a = parse_words("weather", &["--intensity=0.5", "--fps", "24", "--snow"]).unwrap();
+        assert_eq!(a.float("intensity"), Some(0.5));
+        assert_eq!(a.float("fps"), Some(24.0));
+        assert!(a.flag("snow") && !a.flag("json"));
+        // the last of a repeated option wins
+        let a = parse_words("virus", &["--count", "3", "--count=7", "--seed", "42"]).unwrap();
+        assert_eq!((a.int("count"), a.int("seed")), (Some(7), Some(42)));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |app: &str, words: &[&str]| parse_words(app, words).unwrap_err();
+        let bad = |opt: &'static str, value: &str| ArgsError::BadValue { opt, value: value.to_string() };
+        assert_eq!(err("weather", &["--fps"]), ArgsError::MissingValue("fps"));
+        assert_eq!(err("virus", &["--errors", "--seed"]), ArgsError::MissingValue("seed"));
+        assert_eq!(err("virus", &["--seed="]), bad("seed", ""));
+        for (opt, value) in [("intensity", "4.5"), ("intensity", "-1"), ("fps", "0"), ("fps", "fast")] {
+            assert_eq!(err("weather", &[&format!("--{opt}"), value]), bad(opt, value));
+        }
+        assert_eq!(err("virus", &["--count=1001"]), bad("count", "1001"));
+        assert_eq!(err("virus", &["--count", "0"]).to_string(), "\"0\" is not a valid value for --count");
+        // options belong to their app
+        assert_eq!(err("gif", &["--fps", "10"]), ArgsError::Unknown("--fps".to_string()));
+        assert_eq!(err("weather", &["--nope=1"]), ArgsError::Unknown("--nope=1".to_string()));
+        assert_eq!(err("weather", &["snow"]), ArgsError::Unexpected("snow".to_string()));
+        assert_eq!(err("virus", &["-e"]), ArgsError::Unexpected("-e".to_string()));
+        assert_eq!(err("weather", &["--json=yes"]), ArgsError::UnexpectedValue("json"));
+    }
+}
@@ -0,0 +1,455 @@
+//! Who may run which app. Without a policy file every app is public to everyone; with one
+//! (`WEATHER_SSH_AUTH`) apps can be limited to address ranges and made to require a password
+//! or a key from an authorized_keys file.
+//!
+//! ```text
+//! # before any section: applies to every app
+//! deny 203.0.113.0/24
+//!
+//! [gif]
+//! allow 10.0.0.0/8
+//! allow fd00::/8
+//! authorized_keys team_keys
+//! password $argon2id$v=19$m=19456,t=2,p=1$...
+//! ```
+//!
+//! Addresses are refused if any `deny` matches, or if there are `allow` lines and none match,
+//! checking the global lines first. Paths are relative to the policy file, and password hashes
+//! come from `weather_ssh hash-password`. Of the authorized_keys options only `from="..."` with
+//! addresses and ranges is supported; a key with any other option is a parse error. Each app
+//! gets at most one section.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use russh::MethodKind;
+use russh::keys::{
+    PublicKey,
+    ssh_key::{AuthorizedKeys, authorized_keys::ConfigOpts},
+};
+
+use crate::args;
+use crate::config::config;
+
+const HASH_USAGE: &str = "usage: weather_ssh hash-password
+
+Reads a password from the first line of stdin and prints its argon2 hash, for a `password`
+line of the auth policy file.";
+
+/// An address range like `10.0.0.0/8`; a bare address is a range of one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients on a dual stack socket show up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(net.to_bits() as u128, ip.to_bits() as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(net.to_bits(), ip.to_bits(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    prefix == 0 || (a ^ b) >> (bits - prefix) == 0
+}
+impl FromStr for Cidr {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.parse().map_err(|_| ())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().ok().filter(|&p| p <= max).ok_or(())?,
+            None => max,
+        };
+        // clients are matched as plain IPv4, so a range of mapped addresses has to be too
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix >= 96 => Ok(Cidr { addr: v4.into(), prefix: prefix - 96 }),
+            _ => Ok(Cidr { addr, prefix }),
+        }
+    }
+}
+
+/// Address rules, either global or of one app.
+#[derive(Default)]
+struct Access {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+impl Access {
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip)) && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+}
+
+/// A key from an authorized_keys file, with the addresses its `from=` option allows.
+struct AuthorizedKey {
+    key: PublicKey,
+    from: Option<Access>,
+}
+
+#[derive(Default)]
+struct AppPolicy {
+    access: Access,
+    keys: Vec<AuthorizedKey>,
+    /// argon2 hashes in PHC string form.
+    passwords: Vec<String>,
+}
+
+/// What a client has to do to run an app.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Requirement {
+    /// Nothing; the app is public.
+    None,
+    /// One of these auth methods.
+    Auth(Vec<MethodKind>),
+    /// Nothing will do, the address isn't allowed.
+    Denied,
+}
+
+#[derive(Default)]
+pub struct Policy {
+    global: Access,
+    apps: Vec<(String, AppPolicy)>,
+}
+impl Policy {
+    fn app(&self, app: &str) -> Option<&AppPolicy> {
+        self.apps.iter().find(|a| a.0 == app).map(|a| &a.1)
+    }
+    pub fn requirement(&self, ip: IpAddr, app: &str) -> Requirement {
+        let policy = self.app(app);
+        if !self.global.permits(ip) || policy.is_some_and(|p| !p.access.permits(ip)) {
+            return Requirement::Denied;
+        }
+        let mut methods = Vec::new();
+        if let Some(p) = policy {
+            if !p.keys.is_empty() {
+                methods.push(MethodKind::PublicKey);
+            }
+            if !p.passwords.is_empty() {
+                methods.push(MethodKind::Password);
+            }
+        }
+        if methods.is_empty() { Requirement::None } else { Requirement::Auth(methods) }
+    }
+    /// Whether `key` is authorized for `app` from `ip`, going by the key's `from=` option.
+    /// Doesn't check the app's own address rules, see `requirement`.
+    pub fn key_allowed(&self, app: &str, ip: IpAddr, key: &PublicKey) -> bool {
+        self.app(app).is_some_and(|p| {
+            p.keys.iter().any(|k| k.key.key_data() == key.key_data() && k.from.as_ref().is_none_or(|f| f.permits(ip)))
+        })
+    }
+    /// Whether `password` is one of `app`'s. Slow on purpose, so best not run on the runtime.
+    pub fn password_ok(&self, app: &str, password: &str) -> bool {
+        self.app(app).is_some_and(|p| {
+            p.passwords.iter().any(|h| {
+                PasswordHash::new(h).is_ok_and(|h| Argon2::default().verify_password(password.as_bytes(), &h).is_ok())
+            })
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Io(PathBuf, io::Error),
+    Parse { file: String, line: usize, message: String },
+}
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Io(path, e) => write!(f, "could not read {}: {e}", path.display()),
+            PolicyError::Parse { file, line, message } => write!(f, "{file}:{line}: {message}"),
+        }
+    }
+}
+impl std::error::Error for PolicyError {}
+
+/// Parses a policy file; `dir` is where relative authorized_keys paths start from.
+pub fn parse_policy(file: &str, data: &str, dir: &Path) -> Result<Policy, PolicyError> {
+    let err = |line: usize, message: String| PolicyError::Parse {
+        file: file.to_string(),
+        line,
+        message,
+    };
+    let mut policy = Policy::default();
+    // where each app's section starts
+    let mut sections = HashMap::new();
+    for (n, line) in data.lines().enumerate() {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // a misspelt section would leave the app it meant public
+            let Some(app) = args::spec(name.trim()) else {
+                let names: Vec<&str> = args::APPS.iter().map(|a| a.name).collect();
+                return Err(err(n, format!("unknown app [{}], expected one of {}", name.trim(), names.join(", "))));
+            };
+            // and only the first of two sections would be used
+            if let Some(first) = sections.insert(app.name, n) {
+                return Err(err(n, format!("[{}] again, it already starts on line {first}", app.name)));
+            }
+            policy.apps.push((app.name.to_string(), AppPolicy::default()));
+            continue;
+        }
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        let cidr = || value.parse::<Cidr>().map_err(|_| err(n, format!("\"{value}\" is not an address or range")));
+        match (key, policy.apps.last_mut()) {
+            ("allow", Some((_, app))) => app.access.allow.push(cidr()?),
+            ("allow", None) => policy.global.allow.push(cidr()?),
+            ("deny", Some((_, app))) => app.access.deny.push(cidr()?),
+            ("deny", None) => policy.global.deny.push(cidr()?),
+            ("authorized_keys", Some((_, app))) => {
+                let path = dir.join(value);
+                let entries = AuthorizedKeys::read_file(&path).map_err(|e| err(n, format!("{}: {e}", path.display())))?;
+                for (i, entry) in entries.iter().enumerate() {
+                    let from = key_options(entry.config_opts())
+                        .map_err(|e| err(n, format!("{}, key {}: {e}", path.display(), i + 1)))?;
+                    app.keys.push(AuthorizedKey { key: entry.public_key().clone(), from });
+                }
+            }
+            ("password", Some((_, app))) => {
+                PasswordHash::new(value).map_err(|_| err(n, "expected a hash from `weather_ssh hash-password`".to_string()))?;
+                app.passwords.push(value.to_string());
+            }
+            ("authorized_keys" | "password", None) => {
+                return Err(err(n, format!("`{key}` outside of an [app]")));
+            }
+            _ => return Err(err(n, format!("unknown setting `{key}`"))),
+        }
+    }
+    Ok(policy)
+}
+
+/// The addresses an authorized_keys entry's options allow it from. Only `from=` with
+/// addresses and ranges is understood; anything else is refused rather than ignored, since
+/// ignoring it would let the key do more than its owner meant.
+fn key_options(opts: &ConfigOpts) -> Result<Option<Access>, String> {
+    let mut from = None;
+    for opt in opts.iter() {
+        let (name, value) = opt.split_once('=').unwrap_or((opt, ""));
+        if !name.eq_ignore_ascii_case("from") {
+            return Err(format!("option `{name}` isn't supported, only from=\"...\""));
+        }
+        let patterns = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .ok_or_else(|| format!("from={value} needs quotes"))?;
+        let access: &mut Access = from.get_or_insert_with(Access::default);
+        for pattern in patterns.split(',') {
+            let (list, pattern) = match pattern.strip_prefix('!') {
+                Some(p) => (&mut access.deny, p),
+                None => (&mut access.allow, pattern),
+            };
+            let cidr = pattern
+                .parse()
+                .map_err(|_| format!("from=\"{pattern}\" is not an address or range, host names aren't supported"))?;
+            list.push(cidr);
+        }
+    }
+    // like sshd, a key with only negated patterns matches nowhere
+    if from.as_ref().is_some_and(|f: &Access| f.allow.is_empty()) {
+        return Err("from= needs at least one address or range that isn't negated".to_string());
+    }
+    Ok(from)
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Loads the policy file named by `WEATHER_SSH_AUTH`, if any.
+pub fn init_policy() -> Result<(), PolicyError> {
+    let policy = match &config().auth_file {
+        Some(path) => {
+            let data = fs::read_to_string(path).map_err(|e| PolicyError::Io(path.clone(), e))?;
+            let dir = path.parent().unwrap_or(Path::new("."));
+            parse_policy(&path.display().to_string(), &data, dir)?
+        }
+        None => Policy::default(),
+    };
+    let _ = POLICY.set(policy);
+    Ok(())
+}
+pub fn policy() -> &'static Policy {
+    POLICY.get_or_init(Policy::default)
+}
+
+/// `weather_ssh hash-password`
+pub fn hash_password(args: &[String]) -> ExitCode {
+    if !args.is_empty() {
+        eprintln!("{HASH_USAGE}");
+        return ExitCode::FAILURE;
+    }
+    let mut password = String::new();
+    if io::stdin().lock().read_line(&mut password).is_err() {
+        eprintln!("could not read the password");
+        return ExitCode::FAILURE;
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => {
+            println!("{hash}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("could not hash the password: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_v4() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains(ip("10.1.0.0")) && net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.0")) && !net.contains(ip("11.1.0.0")));
+        let one = cidr("192.0.2.7/32");
+        assert_eq!(one, cidr("192.0.2.7"));
+        assert!(one.contains(ip("192.0.2.7")) && !one.contains(ip("192.0.2.6")));
+        let all = cidr("0.0.0.0/0");
+        assert!(all.contains(ip("255.255.255.255")) && all.contains(ip("1.2.3.4")));
+        // a v4 range never matches a real v6 client
+        assert!(!all.contains(ip("2001:db8::1")));
+        for bad in ["10.0.0.0/33", "10.0.0/8", "10.0.0.0/", "10.0.0.0/-1", "", "example.org"] {
+            assert_eq!(bad.parse::<Cidr>(), Err(()), "{bad}");
+        }
+    }
+
+    #[test]
+    fn cidr_v6() {
+        let net = cidr("fd00::/8");
+        assert!(net.contains(ip("fd12:3456::1")) && !net.contains(ip("fe80::1")));
+        let one = cidr("2001:db8::1/128");
+        assert!(one.contains(ip("2001:db8::1")) && !one.contains(ip("2001:db8::2")));
+        let all = cidr("::/0");
+        assert!(all.contains(ip("2001:db8::1")) && !all.contains(ip("10.0.0.1")));
+        assert_eq!("::/129".parse::<Cidr>(), Err(()));
+    }
+
+    #[test]
+    fn cidr_mapped_v4() {
+        // dual stack clients arrive mapped into v6
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.9.8.7")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+        assert!(cidr("192.0.2.1").contains(ip("::ffff:192.0.2.1")));
+        // and ranges can be written mapped too
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+    }
+
+    const HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHRzYWx0$0Gv+UsbhRPhVdvb0ABLqvSmTkwjkqz9GDfiVuH3BONo";
+
+    fn policy(data: &str) -> Result<Policy, PolicyError> {
+        parse_policy("test", data, Path::new("."))
+    }
+
+    #[test]
+    fn requirement() {
+        let p = policy(&format!(
+            "deny 203.0.113.0/24\n\n[gif]\nallow 10.0.0.0/8\npassword {HASH}\n\n[virus]\ndeny 10.0.0.66\n"
+        ))
+        .unwrap();
+        let (inside, outside, blocked) = (ip("10.0.0.1"), ip("192.0.2.1"), ip("203.0.113.5"));
+        assert_eq!(p.requirement(inside, "gif"), Requirement::Auth(vec![MethodKind::Password]));
+        assert_eq!(p.requirement(outside, "gif"), Requirement::Denied);
+        assert_eq!(p.requirement(inside, "virus"), Requirement::None);
+        assert_eq!(p.requirement(ip("10.0.0.66"), "virus"), Requirement::Denied);
+        assert_eq!(p.requirement(outside, "weather"), Requirement::None);
+        // the global rules apply to every app
+        assert_eq!(p.requirement(blocked, "weather"), Requirement::Denied);
+        assert_eq!(p.requirement(ip("::ffff:203.0.113.5"), "help"), Requirement::Denied);
+        // without a file everything is public
+        assert_eq!(Policy::default().requirement(blocked, "gif"), Requirement::None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let line = |data: &str| match policy(data) {
+            Err(PolicyError::Parse { line, message, .. }) => (line, message),
+            other => panic!("{data:?} parsed: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(line("# typo\n[wether]\nallow 10.0.0.0/8").0, 2);
+        assert!(line("[dashbaord]").1.contains("unknown app [dashbaord]"));
+        assert_eq!(line("[gif]\nallow 10.0.0.0/40").0, 2);
+        assert_eq!(line("password x").0, 1);
+        assert_eq!(line("[gif]\npassword not-a-hash").0, 2);
+        assert_eq!(line("[gif]\nallwo 10.0.0.0/8").0, 2);
+        assert!(policy("[ gif ]\n[weather]\n").is_ok());
+        // a second section for an app would be silently ignored
+        let (n, message) = line("[gif]\nallow 10.0.0.0/8\n\n[weather]\n[ gif ]\npassword x");
+        assert_eq!(n, 5);
+        assert!(message.contains("line 1"), "{message}");
+    }
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIBumVF+1W9ILWZW9ylK89WeHHfz1PL+6MD40NWEgM5bq";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIPm6xZGjlCKsPOeq6S9JDCh7xjeiDlF4soffDowX71cF";
+
+    /// Parses a policy for [gif] that uses an authorized_keys file holding `keys`.
+    fn with_keys(name: &str, keys: &str) -> Result<Policy, PolicyError> {
+        let dir = std::env::temp_dir().join(format!("weather_ssh-auth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(name), keys).unwrap();
+        parse_policy("test", &format!("[gif]\nauthorized_keys {name}\n"), &dir)
+    }
+
+    fn key(data: &str) -> PublicKey {
+        PublicKey::from_openssh(&format!("ssh-ed25519 {data}")).unwrap()
+    }
+
+    #[test]
+    fn key_from() {
+        let keys = format!("from=\"10.0.0.0/8,!10.0.0.66\" ssh-ed25519 {KEY} alice\nssh-ed25519 {OTHER_KEY}\n");
+        let p = with_keys("from", &keys).unwrap();
+        assert!(p.key_allowed("gif", ip("10.1.2.3"), &key(KEY)));
+        assert!(p.key_allowed("gif", ip("::ffff:10.1.2.3"), &key(KEY)));
+        assert!(!p.key_allowed("gif", ip("10.0.0.66"), &key(KEY)));
+        assert!(!p.key_allowed("gif", ip("192.0.2.1"), &key(KEY)));
+        // a key without from= works from anywhere the app's own rules allow
+        assert!(p.key_allowed("gif", ip("192.0.2.1"), &key(OTHER_KEY)));
+        assert!(!p.key_allowed("weather", ip("10.1.2.3"), &key(KEY)));
+    }
+
+    #[test]
+    fn key_options_refused() {
+        // ssh-key only looks for options in front of a key that has a comment
+        let message = |name: &str, options: &str| match with_keys(name, &format!("{options} ssh-ed25519 {KEY} bob\n")) {
+            Err(PolicyError::Parse { line, message, .. }) => {
+                assert_eq!(line, 2);
+                message
+            }
+            other => panic!("{options} parsed: {:?}", other.map(|_| ())),
+        };
+        assert!(message("restrict", "restrict").contains("`restrict`"));
+        assert!(message("no-pty", "from=\"10.0.0.0/8\",no-pty").contains("`no-pty`"));
+        assert!(message("host", "from=\"*.example.org\"").contains("*.example.org"));
+        assert!(message("negated", "from=\"!10.0.0.0/8\"").contains("negated"));
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::HashSet, fs, process::ExitCode};
+
+use fontdue::{Font, FontSettings};
+
+use crate::frame::{FULL_INK, is_narrow};
+
+const USAGE: &str = "usage: weather_ssh calibrate <font.ttf|otf> [--size PX] [--grid N] [--chars CHARS] [--bitmap] [--out FILE]
+
+Rasterises every char in CHARS (default: printable ASCII) at PX pixels (default 32) and
+writes one `c:ink` line per glyph in the format of lines.txt, where a glyph covering its
+whole cell has ink 90720. With --grid N the cell is also split into NxN parts and their
+ink is appended row by row as `c:ink:a,b,c,...`.
+
+With --bitmap, writes the glyphs themselves instead, as a screenshot font in the format of
+font.txt.";
+
+/// Coverage of each glyph's cell, as a whole and per sub-cell.
+pub struct Measured {
+    pub c: char,
+    pub ink: i32,
+    pub grid: Vec<i32>,
+}
+
+/// A monospace cell of a font at some size.
+#[derive(Clone, Copy)]
+pub struct Cell {
+    pub w: usize,
+    pub h: usize,
+    /// Pixels from the top down to the baseline.
+    baseline: i32,
+}
+impl Cell {
+    /// The cell of `font` at `px` pixels, `None` for fonts without horizontal line metrics,
+    /// which can't be laid out in lines.
+    pub fn of(font: &Font, px: f32) -> Option<Cell> {
+        let line = font.horizontal_line_metrics(px)?;
+        Some(Cell {
+            w: font.metrics('M', px).advance_width.round().max(1.0) as usize,
+            h: (line.ascent - line.descent).ceil().max(1.0) as usize,
+            baseline: line.ascent.round() as i32,
+        })
+    }
+}
+/// Coverage (0-255 per pixel, but may add up past 255) of `c` drawn in `cell`.
+fn draw_cell(font: &Font, px: f32, cell: Cell, c: char) -> Vec<u32> {
+    let (cell_w, cell_h, baseline) = (cell.w, cell.h, cell.baseline);
+    let (m, bitmap) = font.rasterize(c, px);
+    let mut cell = vec![0u32; cell_w * cell_h];
+    for gy in 0..m.height {
+        for gx in 0..m.width {
+            let x = m.xmin + gx as i32;
+            // bitmap rows go down from the glyph's top edge, ymin is its bottom above the baseline
+            let y = baseline - (m.ymin + m.height as i32) + gy as i32;
+            if x >= 0 && y >= 0 && (x as usize) < cell_w && (y as usize) < cell_h {
+                cell[x as usize + y as usize * cell_w] += bitmap[gx + gy * m.width] as u32;
+            }
+        }
+    }
+    cell
+}
+fn has_glyph(font: &Font, c: char) -> bool {
+    c == ' ' || font.lookup_glyph_index(c) != 0
+}
+
+/// Measures `chars` as drawn in `cell` of `font` at `px` pixels.
+pub fn measure(font: &Font, px: f32, cell: Cell, grid: usize, chars: &[char]) -> Vec<Measured> {
+    let (cell_w, cell_h) = (cell.w, cell.h);
+    chars
+        .iter()
+        .filter(|&&c| has_glyph(font, c))
+        .map(|&c| {
+            let cell = draw_cell(font, px, cell, c);
+            let ink = |x0: usize, x1: usize, y0: usize, y1: usize| {
+                let mut sum = 0u64;
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        sum += cell[x + y * cell_w] as u64;
+                    }
+                }
+                let area = ((x1 - x0) * (y1 - y0)).max(1) as u64;
+                (sum * FULL_INK as u64 / (area * 255)) as i32
+            };
+            let mut sub = Vec::new();
+            if grid > 1 {
+                for gy in 0..grid {
+                    for gx in 0..grid {
+                        sub.push(ink(
+                            gx * cell_w / grid,
+                            (gx + 1) * cell_w / grid,
+                            gy * cell_h / grid,
+                            (gy + 1) * cell_h / grid,
+                        ));
+                    }
+                }
+            }
+            Measured {
+                c,
+                ink: ink(0, cell_w, 0, cell_h),
+                grid: sub,
+            }
+        })
+        .collect()
+}
+
+pub fn to_table(measured: &[Measured]) -> String {
+    let mut out = String::new();
+    for m in measured {
+        out += &format!("{}:{}", m.c, m.ink);
+        if !m.grid.is_empty() {
+            let grid: Vec<String> = m.grid.iter().map(|v| v.to_string()).collect();
+            out += &format!(":{}", grid.join(","));
+        }
+        out += "\n";
+    }
+    out
+}
+
+/// `chars` drawn at `px` pixels as a bitmap font in the format of font.txt.
+pub fn to_bitmap_font(font: &Font, px: f32, cell: Cell, chars: &[char]) -> String {
+    let mut out = format!("{}x{}\n", cell.w, cell.h);
+    for &c in chars.iter().filter(|&&c| has_glyph(font, c)) {
+        let pixels: String = draw_cell(font, px, cell, c)
+            .iter()
+            .map(|&v| char::from_digit(v.min(255) * 15 / 255, 16).unwrap())
+            .collect();
+        out += &format!("{c}:{pixels}\n");
+    }
+    out
+}
+
+/// A pixel size, which has to be finite and above 0 for there to be anything to measure.
+fn parse_size(value: &str) -> Option<f32> {
+    value.parse().ok().filter(|v: &f32| v.is_finite() && *v > 0.0)
+}
+
+/// `weather_ssh calibrate ...`, `args` starts after the subcommand.
+pub fn run(args: &[String]) -> ExitCode {
+    let mut font_path = None;
+    let mut px = 32.0;
+    let mut grid = 1;
+    let mut chars: Vec<char> = (' '..='~').collect();
+    let mut out = None;
+    let mut bitmap = false;
+    let mut args = args.iter();
+    while let Some(a) = args.next() {
+        let value = match a.as_str() {
+            "--bitmap" => {
+                bitmap = true;
+                continue;
+            }
+            "--size" | "--grid" | "--chars" | "--out" => match args.next() {
+                Some(v) => v,
+                None => {
+                    eprintln!("{a} needs a value\n\n{USAGE}");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if font_path.is_none() && !a.starts_with("--") => {
+                font_path = Some(a.clone());
+                continue;
+            }
+            _ => {
+                eprintln!("unexpected argument {a}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let ok = match a.as_str() {
+            "--size" => parse_size(value).map(|v| px = v).is_some(),
+            "--grid" => value.parse().map(|v| grid = v).is_ok(),
+            "--chars" => {
+                chars = value.chars().collect();
+                true
+            }
+            _ => {
+                out = Some(value.clone());
+                true
+            }
+        };
+        if !ok {
+            eprintln!("invalid value for {a}: {value}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let Some(font_path) = font_path else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    let font = match fs::read(&font_path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| Font::from_bytes(bytes, FontSettings::default()).map_err(|e| e.to_string()))
+    {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("could not load {font_path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(cell) = Cell::of(&font, px) else {
+        eprintln!("{font_path} has no horizontal line metrics, only fonts for horizontal text can be measured\n\n{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    // the frame can only place glyphs that take exactly one column, and each once is enough
+    let mut seen = HashSet::new();
+    chars.retain(|&c| is_narrow(c) && seen.insert(c));
+    let table = if bitmap {
+        to_bitmap_font(&font, px, cell, &chars)
+    } else {
+        to_table(&measure(&font, px, cell, grid, &chars))
+    };
+    match out {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, table) {
+                eprintln!("could not write {path}: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        None => print!("{table}"),
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("32"), Some(32.0));
+        assert_eq!(parse_size("10.5"), Some(10.5));
+        for bad in ["0", "-0", "-12", "NaN", "nan", "inf", "-inf", "1e40", "", "big"] {
+            assert_eq!(parse_size(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{env, net::SocketAddr, path::PathBuf, str::FromStr, sync::OnceLock, time::Duration};
+
+use crate::logging::{Level, LogFormat};
+use crate::seed::SeedSource;
+
+/// Server settings, read once from `WEATHER_SSH_*` environment variables.
+pub struct Config {
+    /// Where the `gif` app looks for animations. `WEATHER_SSH_MEDIA`, default `media/` next to the executable.
+    pub media_dir: PathBuf,
+    /// Directory whose lines.txt / colors.txt replace the built-in ones. `WEATHER_SSH_DATA`.
+    pub data_dir: Option<PathBuf>,
+    /// Name of the palette in colors.txt to use, the first one if unset. `WEATHER_SSH_PALETTE`.
+    pub palette: Option<String>,
+    /// Frame rate sessions aim for when the client and the CPU keep up. `WEATHER_SSH_FPS`, default 30.
+    pub fps: f64,
+    /// Share of all cores rendering may use before quality is lowered. `WEATHER_SSH_CPU_BUDGET`, default 0.8.
+    pub cpu_budget: f64,
+    /// How long shutdown waits for clients to take their goodbye. `WEATHER_SSH_SHUTDOWN_TIMEOUT`
+    /// in seconds, default 5.
+    pub shutdown_timeout: Duration,
+    /// Printed to every session on shutdown, after the terminal is restored. `WEATHER_SSH_GOODBYE`.
+    pub goodbye: Option<String>,
+    /// Most verbose level logged. `WEATHER_SSH_LOG`: error, warn, info (default) or debug.
+    pub log_level: Level,
+    /// `WEATHER_SSH_LOG_FORMAT`: text (default) or json.
+    pub log_format: LogFormat,
+    /// Whether client IPs and usernames may be logged. `WEATHER_SSH_LOG_IDENTITY=1`, off by default.
+    pub log_identity: bool,
+    /// Where to serve Prometheus metrics over HTTP, e.g. `127.0.0.1:9100`. `WEATHER_SSH_METRICS`,
+    /// off by default.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Most session channels open across the server. `WEATHER_SSH_MAX_SESSIONS`, default 64.
+    pub max_sessions: usize,
+    /// Most connections open from one IPv4 address or IPv6 /64. `WEATHER_SSH_MAX_CONNECTIONS_PER_IP`,
+    /// default 4.
+    pub max_connections_per_ip: usize,
+    /// New connections one IP may open per minute. `WEATHER_SSH_CONNECT_RATE`, default 20.
+    pub connect_rate: f64,
+    /// New connections one IP may open at once before the rate applies. `WEATHER_SSH_CONNECT_BURST`,
+    /// default 5.
+    pub connect_burst: f64,
+    /// Most session channels open on one connection. `WEATHER_SSH_MAX_CHANNELS`, default 2.
+    pub max_channels: usize,
+    /// Auth policy file, see `auth`. `WEATHER_SSH_AUTH`, every app is public if unset.
+    pub auth_file: Option<PathBuf>,
+    /// Seed for sessions that don't pick one. `WEATHER_SSH_SEED`: a number, `daily` for a scene
+    /// of the day, or `random` (default).
+    pub seed: SeedSource,
+    /// Where session recordings go, see `record`. `WEATHER_SSH_RECORD`, off if unset.
+    pub record_dir: Option<PathBuf>,
+    /// Record 1 in this many terminal sessions. `WEATHER_SSH_RECORD_SAMPLE`, default 1.
+    pub record_sample: u64,
+    /// Size at which a recording stops. `WEATHER_SSH_RECORD_MAX_BYTES`, default 20 MB.
+    pub record_max_bytes: u64,
+    /// Size the recordings directory is kept under by deleting the oldest ones.
+    /// `WEATHER_SSH_RECORD_MAX_TOTAL`, default 1 GB.
+    pub record_max_total: u64,
+}
+impl Config {
+    pub fn from_env() -> Config {
+        Config {
+            media_dir: env::var_os("WEATHER_SSH_MEDIA")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| exe_dir().join("media")),
+            data_dir: env::var_os("WEATHER_SSH_DATA").map(PathBuf::from),
+            palette: env::var("WEATHER_SSH_PALETTE").ok(),
+            fps: parse_var("WEATHER_SSH_FPS", 30.0f64).clamp(1.0, 120.0),
+            cpu_budget: parse_var("WEATHER_SSH_CPU_BUDGET", 0.8f64).max(0.01),
+            shutdown_timeout: Duration::from_secs_f64(parse_var("WEATHER_SSH_SHUTDOWN_TIMEOUT", 5.0f64).clamp(0.0, 600.0)),
+            goodbye: env::var("WEATHER_SSH_GOODBYE").ok(),
+            log_level: parse_var("WEATHER_SSH_LOG", Level::Info),
+            log_format: parse_var("WEATHER_SSH_LOG_FORMAT", LogFormat::Text),
+            log_identity: matches!(env::var("WEATHER_SSH_LOG_IDENTITY").as_deref(), Ok("1" | "true" | "yes")),
+            metrics_addr: env::var("WEATHER_SSH_METRICS").ok().and_then(|v| {
+                v.parse()
+                    .map_err(|_| eprintln!("ignoring invalid WEATHER_SSH_METRICS={v}"))
+                    .ok()
+            }),
+            max_sessions: parse_var("WEATHER_SSH_MAX_SESSIONS", 64),
+            max_connections_per_ip: parse_var("WEATHER_SSH_MAX_CONNECTIONS_PER_IP", 4),
+            connect_rate: parse_var("WEATHER_SSH_CONNECT_RATE", 20.0f64).max(0.0),
+            connect_burst: parse_var("WEATHER_SSH_CONNECT_BURST", 5.0f64).max(1.0),
+            max_channels: parse_var("WEATHER_SSH_MAX_CHANNELS", 2),
+            auth_file: env::var_os("WEATHER_SSH_AUTH").map(PathBuf::from),
+            seed: parse_var("WEATHER_SSH_SEED", SeedSource::Random),
+            record_dir: env::var_os("WEATHER_SSH_RECORD").map(PathBuf::from),
+            record_sample: parse_var("WEATHER_SSH_RECORD_SAMPLE", 1u64).max(1),
+            record_max_bytes: parse_var("WEATHER_SSH_RECORD_MAX_BYTES", 20_000_000),
+            record_max_total: parse_var("WEATHER_SSH_RECORD_MAX_TOTAL", 1_000_000_000),
+        }
+    }
+}
+/// The value of `name`, or `default` if it is unset or doesn't parse.
+fn parse_var<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            eprintln!("ignoring invalid {name}={v}");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+pub fn config() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(Config::from_env)
+}
+pub fn exe_dir() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+        .unwrap_or_default()
+}
@@ -0,0 +1,339 @@
+use std::sync::OnceLock;
+
+use crate::frame::{Frame, FromRGB, ToAnsi, ToRGB};
+
+/// How RGB pixels are spread over the glyphs/colours a texel type can show.
+///
+/// The error diffusion modes look best on still images but the pattern moves whenever the
+/// input changes; the ordered modes use a threshold fixed to the screen position, so
+/// unchanged areas of an animated scene keep exactly the same texels from frame to frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    /// Ordered dithering with a 2x2, 4x4 or 8x8 Bayer matrix.
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    /// Ordered dithering with a 32x32 void-and-cluster blue noise mask.
+    BlueNoise,
+}
+impl Dither {
+    /// Threshold in 0..1 for screen position (x, y), `None` for non-ordered modes.
+    pub fn threshold(&self, x: usize, y: usize) -> Option<f32> {
+        match self {
+            Dither::Bayer2 | Dither::Bayer4 | Dither::Bayer8 => {
+                let m = match self {
+                    Dither::Bayer2 => &bayer()[1],
+                    Dither::Bayer4 => &bayer()[2],
+                    _ => &bayer()[3],
+                };
+                let n = m.len();
+                Some((m[y % n][x % n] as f32 + 0.5) / (n * n) as f32)
+            }
+            Dither::BlueNoise => {
+                let m = blue_noise();
+                Some((m[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE] as f32 + 0.5)
+                    / (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE) as f32)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<C> Frame<C>
+where
+    C: Clone + PartialEq + ToAnsi,
+{
+    /// Quantises a `w`x`h` block of RGB pixels into the texels at (x0, y0), clipped to the frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn put_rgb<ST>(
+        &mut self,
+        x0: usize,
+        y0: usize,
+        w: usize,
+        h: usize,
+        pixels: &[[u8; 3]],
+        st: &ST,
+        dither: Dither,
+    ) where
+        C: FromRGB<ST> + ToRGB<ST>,
+    {
+        let w_clip = w.min(self.width.saturating_sub(x0));
+        let h_clip = h.min(self.height.saturating_sub(y0));
+        match dither {
+            Dither::None => {
+                for y in 0..h_clip {
+                    for x in 0..w_clip {
+                        let [r, g, b] = pixels[x + y * w];
+                        let _ = self.set_pixel(x0 + x, y0 + y, r, g, b, st);
+                    }
+                }
+            }
+            Dither::Bayer2 | Dither::Bayer4 | Dither::Bayer8 | Dither::BlueNoise => {
+                for y in 0..h_clip {
+                    for x in 0..w_clip {
+                        let t = dither.threshold(x0 + x, y0 + y).unwrap();
+                        let p = pixels[x + y * w].map(|v| v as f32);
+                        let _ = self.set_texel(x0 + x, y0 + y, ordered::<C, ST>(p, t, st));
+                    }
+                }
+            }
+            Dither::FloydSteinberg | Dither::Atkinson => {
+                let kernel: &[(isize, usize, f32)] = if dither == Dither::FloydSteinberg {
+                    &[(1, 0, 7.0 / 16.0), (-1, 1, 3.0 / 16.0), (0, 1, 5.0 / 16.0), (1, 1, 1.0 / 16.0)]
+                } else {
+                    // only 6/8 of the error is passed on, which keeps contrast up
+                    &[(1, 0, 0.125), (2, 0, 0.125), (-1, 1, 0.125), (0, 1, 0.125), (1, 1, 0.125), (0, 2, 0.125)]
+                };
+                let mut buf: Vec<[f32; 3]> = pixels.iter().map(|p| p.map(|v| v as f32)).collect();
+                for y in 0..h_clip {
+                    // serpentine scan so the error doesn't pile up towards one side
+                    let rtl = y % 2 == 1;
+                    for i in 0..w_clip {
+                        let x = if rtl { w_clip - 1 - i } else { i };
+                        let p = buf[x + y * w];
+                        let t = C::from_rgb(clamp(p[0]), clamp(p[1]), clamp(p[2]), st);
+                        let shown = C::to_rgb(t.0, &t.1, st);
+                        let _ = self.set_texel(x0 + x, y0 + y, t);
+                        let err = [
+                            p[0] - shown[0] as f32,
+                            p[1] - shown[1] as f32,
+                            p[2] - shown[2] as f32,
+                        ];
+                        for &(dx, dy, k) in kernel {
+                            let dx = if rtl { -dx } else { dx };
+                            let nx = x as isize + dx;
+                            let ny = y + dy;
+                            if nx >= 0 && (nx as usize) < w_clip && ny < h_clip {
+                                let q = &mut buf[nx as usize + ny * w];
+                                for c in 0..3 {
+                                    q[c] += err[c] * k;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn clamp(v: f32) -> u8 {
+    v.round().clamp(0.0, 255.0) as u8
+}
+/// Picks between the nearest texel and the one on the other side of `p`,
+/// in the proportion that mixes the two closest to `p`. Exactly representable
+/// colours (e.g. a black sky) therefore never get speckled.
+fn ordered<C, ST>(p: [f32; 3], t: f32, st: &ST) -> (char, C)
+where
+    C: FromRGB<ST> + ToRGB<ST>,
+{
+    let q1 = C::from_rgb(clamp(p[0]), clamp(p[1]), clamp(p[2]), st);
+    let c1 = C::to_rgb(q1.0, &q1.1, st).map(|v| v as f32);
+    let e = [p[0] - c1[0], p[1] - c1[1], p[2] - c1[2]];
+    if e == [0.0; 3] {
+        return q1;
+    }
+    let q2 = C::from_rgb(clamp(p[0] + e[0]), clamp(p[1] + e[1]), clamp(p[2] + e[2]), st);
+    let c2 = C::to_rgb(q2.0, &q2.1, st).map(|v| v as f32);
+    let d = [c2[0] - c1[0], c2[1] - c1[1], c2[2] - c1[2]];
+    let dd = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+    if dd == 0.0 {
+        return q1;
+    }
+    let ratio = ((e[0] * d[0] + e[1] * d[1] + e[2] * d[2]) / dd).clamp(0.0, 1.0);
+    if t < ratio { q2 } else { q1 }
+}
+
+/// Builds the ordered dithering masks, the blue noise one taking a moment; call once at
+/// startup so no session has to wait for them.
+pub fn init() {
+    bayer();
+    blue_noise();
+}
+
+/// The Bayer matrices of size 1, 2, 4 and 8.
+fn bayer() -> &'static [Vec<Vec<u32>>] {
+    static MATRICES: OnceLock<Vec<Vec<Vec<u32>>>> = OnceLock::new();
+    MATRICES.get_or_init(|| {
+        let mut ms = vec![vec![vec![0u32]]];
+        for _ in 0..3 {
+            let m = ms.last().unwrap();
+            let s = m.len();
+            let mut next = vec![vec![0; s * 2]; s * 2];
+            for y in 0..s * 2 {
+                for x in 0..s * 2 {
+                    let q = [0, 2, 3, 1][(x / s) + (y / s) * 2];
+                    next[y][x] = 4 * m[y % s][x % s] + q;
+                }
+            }
+            ms.push(next);
+        }
+        ms
+    })
+}
+
+const BLUE_NOISE_SIZE: usize = 32;
+/// Ranks 0..32*32 laid out by the void-and-cluster method, generated once by `init` or on
+/// first use.
+fn blue_noise() -> &'static [u32] {
+    static MASK: OnceLock<Vec<u32>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        const N: usize = BLUE_NOISE_SIZE;
+        let sigma2 = 2.0 * 1.5f32 * 1.5;
+        let mut kernel = vec![0.0f32; N * N];
+        for y in 0..N {
+            for x in 0..N {
+                let dx = x.min(N - x) as f32;
+                let dy = y.min(N - y) as f32;
+                kernel[x + y * N] = (-(dx * dx + dy * dy) / sigma2).exp();
+            }
+        }
+        let apply = |energy: &mut [f32], p: usize, sign: f32| {
+            let (px, py) = (p % N, p / N);
+            for y in 0..N {
+                for x in 0..N {
+                    energy[x + y * N] += sign * kernel[(x + N - px) % N + ((y + N - py) % N) * N];
+                }
+            }
+        };
+        let pick = |energy: &[f32], pattern: &[bool], want: bool, highest: bool| {
+            (0..N * N)
+                .filter(|&i| pattern[i] == want)
+                .max_by(|&a, &b| {
+                    let o = energy[a].total_cmp(&energy[b]);
+                    if highest { o } else { o.reverse() }
+                })
+                .unwrap()
+        };
+
+        // fixed seed so every run produces the same mask
+        let mut seed = 0x2545F491u32;
+        let mut pattern = vec![false; N * N];
+        let mut energy = vec![0.0f32; N * N];
+        let ones = N * N / 10;
+        let mut placed = 0;
+        while placed < ones {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let p = seed as usize % (N * N);
+            if !pattern[p] {
+                pattern[p] = true;
+                apply(&mut energy, p, 1.0);
+                placed += 1;
+            }
+        }
+        // spread the initial points out: move the tightest cluster into the largest void
+        for _ in 0..N * N {
+            let cluster = pick(&energy, &pattern, true, true);
+            pattern[cluster] = false;
+            apply(&mut energy, cluster, -1.0);
+            let void = pick(&energy, &pattern, false, false);
+            pattern[void] = true;
+            apply(&mut energy, void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut rank = vec![0u32; N * N];
+        let (initial, initial_energy) = (pattern.clone(), energy.clone());
+        for r in (0..ones).rev() {
+            let cluster = pick(&energy, &pattern, true, true);
+            pattern[cluster] = false;
+            apply(&mut energy, cluster, -1.0);
+            rank[cluster] = r as u32;
+        }
+        let (mut pattern, mut energy) = (initial, initial_energy);
+        for r in ones..N * N {
+            let void = pick(&energy, &pattern, false, false);
+            pattern[void] = true;
+            apply(&mut energy, void, 1.0);
+            rank[void] = r as u32;
+        }
+        rank
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A texel that is either black or white.
+    #[derive(Clone, PartialEq, Debug)]
+    struct Bw(bool);
+    impl ToAnsi for Bw {
+        fn to_ansi(&self) -> String {
+            String::new()
+        }
+    }
+    impl FromRGB<()> for Bw {
+        fn from_rgb(r: u8, g: u8, b: u8, _: &()) -> (char, Self) {
+            (' ', Bw(r as u32 + g as u32 + b as u32 >= 3 * 128))
+        }
+    }
+    impl ToRGB<()> for Bw {
+        fn to_rgb(_: char, c: &Self, _: &()) -> [u8; 3] {
+            [if c.0 { 255 } else { 0 }; 3]
+        }
+    }
+
+    const ALL: [Dither; 7] = [
+        Dither::None,
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Bayer2,
+        Dither::Bayer4,
+        Dither::Bayer8,
+        Dither::BlueNoise,
+    ];
+
+    fn dithered(pixels: &[[u8; 3]], size: usize, dither: Dither) -> Frame<Bw> {
+        let mut f = Frame::new(size, size, Bw(false));
+        f.put_rgb(0, 0, size, size, pixels, &(), dither);
+        f
+    }
+    fn white(f: &Frame<Bw>) -> f32 {
+        f.texels.iter().filter(|t| t.1.0).count() as f32 / f.texels.len() as f32
+    }
+
+    #[test]
+    fn mid_grey_is_half_white() {
+        // 32 fits every mask a whole number of times
+        let grey = vec![[128; 3]; 32 * 32];
+        for dither in &ALL[1..] {
+            let white = white(&dithered(&grey, 32, *dither));
+            assert!((0.45..=0.55).contains(&white), "{dither:?}: {white}");
+        }
+        // and what is shown exactly isn't dithered at all
+        for dither in ALL {
+            assert_eq!(white(&dithered(&[[255; 3]; 64], 8, dither)), 1.0, "{dither:?}");
+            assert_eq!(white(&dithered(&[[0; 3]; 64], 8, dither)), 0.0, "{dither:?}");
+        }
+    }
+
+    #[test]
+    fn none_is_nearest() {
+        let pixels: Vec<[u8; 3]> = (0..16 * 16).map(|i| [i as u8, (i * 7) as u8, 255 - i as u8]).collect();
+        let f = dithered(&pixels, 16, Dither::None);
+        for (t, p) in f.texels.iter().zip(&pixels) {
+            assert_eq!(*t, Bw::from_rgb(p[0], p[1], p[2], &()));
+        }
+    }
+
+    #[test]
+    fn masks() {
+        for (dither, n) in [(Dither::Bayer2, 2), (Dither::Bayer4, 4), (Dither::Bayer8, 8), (Dither::BlueNoise, 32)] {
+            // every threshold of the mask once per tile
+            let mut t: Vec<f32> = (0..n * n).map(|i| dither.threshold(i % n, i / n).unwrap()).collect();
+            t.sort_by(f32::total_cmp);
+            let expected: Vec<f32> = (0..n * n).map(|i| (i as f32 + 0.5) / (n * n) as f32).collect();
+            assert_eq!(t, expected, "{dither:?}");
+            assert_eq!(dither.threshold(3, 5), dither.threshold(3 + n, 5 + 2 * n));
+        }
+        assert_eq!(Dither::Atkinson.threshold(0, 0), None);
+    }
+}
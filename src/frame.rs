@@ -1,8 +1,31 @@
 use std::{
-    env, fs, i16, ops::{Div, Mul}
+    collections::HashMap,
+    fmt,
+    ops::{Div, Mul},
+    path::Path,
 };
 
-use image::{GenericImageView, ImageReader};
+use image::DynamicImage;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::picture::{ImageError, ImageOptions, load_image, resample};
+use crate::lut::Lut;
+use crate::shape::FromRGBShape;
+use crate::termdata::TerminalData;
+
+/// Stored in the cell right of a double-width glyph; the terminal draws the glyph over both cells.
+pub const WIDE_TAIL: char = '\0';
+
+/// A texel or pixel was set outside the frame or layer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutOfBounds;
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "position is outside the frame")
+    }
+}
+impl std::error::Error for OutOfBounds {}
 
 pub struct Frame<C>
 where
@@ -11,6 +34,10 @@ where
     pub width: usize,
     pub height: usize,
     pub texels: Vec<(char, C)>,
+    /// Combining marks etc. that follow the base char of a texel (grapheme clusters).
+    pub clusters: HashMap<usize, String>,
+    /// Distance of what was drawn into each texel via the `*_depth` setters, smaller is nearer.
+    pub depth: Vec<f32>,
 }
 impl<C> Frame<C>
 where
@@ -22,18 +49,112 @@ where
             width,
             height,
             texels,
+            clusters: HashMap::new(),
+            depth: vec![f32::INFINITY; width * height],
         }
     }
-    pub fn set_texel(&mut self, x: usize, y: usize, c: (char, C)) -> Result<(), ()> {
+    pub fn clear_depth(&mut self) {
+        self.depth.fill(f32::INFINITY);
+    }
+    /// Sets the texel only if `z` is nearer than what is already there; returns whether it was.
+    pub fn set_texel_depth(&mut self, x: usize, y: usize, z: f32, c: (char, C)) -> Result<bool, OutOfBounds> {
         if x < self.width && y < self.height {
-            self.texels[x + y * self.width] = c;
+            let i = x + y * self.width;
+            if z < self.depth[i] {
+                self.depth[i] = z;
+                self.set_texel(x, y, c)?;
+                return Ok(true);
+            }
+            Ok(false)
+        } else {
+            Err(OutOfBounds)
+        }
+    }
+    pub fn set_texel(&mut self, x: usize, y: usize, c: (char, C)) -> Result<(), OutOfBounds> {
+        if x < self.width && y < self.height {
+            let w = char_width(c.0);
+            self.write_cell(x, y, c, String::new(), w);
             Ok(())
         } else {
-            Err(())
+            Err(OutOfBounds)
+        }
+    }
+    /// Writes `s` starting at (x, y), one grapheme cluster per cell (two for wide ones).
+    /// Stops at the right edge; returns the number of columns used.
+    pub fn put_str(&mut self, x: usize, y: usize, s: &str, c: C) -> usize {
+        if y >= self.height {
+            return 0;
+        }
+        let mut cx = x;
+        for g in s.graphemes(true) {
+            let mut chars = g.chars();
+            let first = chars.next().unwrap();
+            let w = if first.is_control() { 1 } else { g.width() };
+            if cx + w.max(1) > self.width {
+                break;
+            }
+            self.write_cell(cx, y, (first, c.clone()), chars.collect(), w);
+            cx += w.max(1);
+        }
+        cx - x
+    }
+    fn write_cell(&mut self, x: usize, y: usize, c: (char, C), tail: String, w: usize) {
+        let i = x + y * self.width;
+        self.clear_cell(x, y);
+        let (ch, col) = c;
+        if ch == WIDE_TAIL || ch.is_control() {
+            self.texels[i] = (' ', col);
+            return;
+        }
+        let (ch, tail) = if w == 0 {
+            // lone combining mark, give it a base to sit on
+            (' ', format!("{ch}{tail}"))
+        } else {
+            (ch, tail)
+        };
+        if w >= 2 {
+            if x + 1 >= self.width {
+                self.texels[i] = (' ', col);
+                return;
+            }
+            self.clear_cell(x + 1, y);
+            self.texels[i + 1] = (WIDE_TAIL, col.clone());
+        }
+        self.texels[i] = (ch, col);
+        if !tail.is_empty() {
+            self.clusters.insert(i, tail);
+        }
+    }
+    /// Blanks whatever half of a wide glyph is left over when (x, y) gets overwritten.
+    fn clear_cell(&mut self, x: usize, y: usize) {
+        let i = x + y * self.width;
+        self.clusters.remove(&i);
+        if self.texels[i].0 == WIDE_TAIL {
+            if x > 0 {
+                self.texels[i - 1].0 = ' ';
+                self.clusters.remove(&(i - 1));
+            }
+        } else if x + 1 < self.width && self.texels[i + 1].0 == WIDE_TAIL {
+            self.texels[i + 1].0 = ' ';
+        }
+    }
+    /// The text the terminal shows in this cell, empty for the right half of a wide glyph.
+    pub fn texel_str(&self, x: usize, y: usize) -> String {
+        let i = x + y * self.width;
+        match self.texels[i].0 {
+            WIDE_TAIL => String::new(),
+            c => match self.clusters.get(&i) {
+                Some(tail) => format!("{c}{tail}"),
+                None => c.to_string(),
+            },
         }
     }
-    pub fn set_pixel<ST>(&mut self, x: usize, y: usize, r:u8,g:u8,b:u8,st: &ST) -> Result<(), ()> where C: FromRGB<ST> {
-        self.set_texel(x, y, C::from_rgb(r,g,b,&st))
+    pub fn set_pixel<ST>(&mut self, x: usize, y: usize, r:u8,g:u8,b:u8,st: &ST) -> Result<(), OutOfBounds> where C: FromRGB<ST> {
+        self.set_texel(x, y, C::from_rgb(r,g,b,st))
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_pixel_depth<ST>(&mut self, x: usize, y: usize, z: f32, r:u8,g:u8,b:u8,st: &ST) -> Result<bool, OutOfBounds> where C: FromRGB<ST> {
+        self.set_texel_depth(x, y, z, C::from_rgb(r,g,b,st))
     }
     pub fn render(&self) {
         // execute!(std::io::stdout(), MoveTo(0,0)).unwrap();
@@ -42,13 +163,16 @@ where
             // execute!(std::io::stdout(), cursor::MoveTo(0, y as u16)).unwrap();
             for x in 0..self.width {
                 let t = &self.texels[x + y * self.width];
-                if let Some(l) = &last {
-                    if l == &t.1 {
-                        print!("{}", t.0);
-                        continue;
-                    }
+                if t.0 == WIDE_TAIL {
+                    continue;
+                }
+                if let Some(l) = &last
+                    && l == &t.1
+                {
+                    print!("{}", self.texel_str(x, y));
+                    continue;
                 }
-                print!("{}{}", t.1.to_ansi(), t.0);
+                print!("{}{}", t.1.to_ansi(), self.texel_str(x, y));
                 last = Some(t.1.clone());
             }
         }
@@ -61,114 +185,135 @@ where
             out=format!("{out}\r\x1b[{}d",y+1);
             for x in 0..self.width {
                 let t = &self.texels[x + y * self.width];
-                if let Some(l) = &last {
-                    if l == &t.1 {
-                        out=format!("{out}{}", t.0);
-                        continue;
-                    }
+                if t.0 == WIDE_TAIL {
+                    continue;
+                }
+                if let Some(l) = &last
+                    && l == &t.1
+                {
+                    out=format!("{out}{}", self.texel_str(x, y));
+                    continue;
                 }
-                out=format!("{out}{}{}", t.1.to_ansi(), t.0);
+                out=format!("{out}{}{}", t.1.to_ansi(), self.texel_str(x, y));
                 last = Some(t.1.clone());
             }
         }
         out
     }
 }
-type TerminalData = (Vec<[u8; 3]>, Vec<(char, i32)>, i32);
-pub fn read_term_data() -> TerminalData {
-    let exe_path=env::current_exe().unwrap().parent().unwrap().to_str().unwrap().to_string();
-    println!("{exe_path}");
-    let data = fs::read_to_string(format!("{exe_path}/lines.txt"))
-        .expect("Unable to read file");
-    let mut chars: Vec<(char, i32)> = Vec::new();
-    let mut c = data.chars();
-    let mut string: String = String::new();
-    let mut biggest = 90720;
-    while let Some(h) = c.next() {
-        if h == '\n' {
-            let n = string.chars().next().unwrap();
-            // println!(".{exe_path}.", string[n.len_utf8() + 1..string.len()].to_string());
-            let h2 = string[n.len_utf8() + 1..string.len()]
-                .parse::<i32>()
-                .unwrap();
-            biggest = if h2 > biggest { h2 } else { biggest };
-            chars.push((n, h2));
-            string = String::new();
-        } else {
-            string += h.to_string().as_str();
-        }
-    }
-    let data2 = fs::read_to_string(format!("{exe_path}/colors.txt"))
-        .expect("Unable to read file");
-    let mut colors: Vec<[u8; 3]> = Vec::new();
-    let mut c2 = data2.chars();
-    let mut string2: String = String::new();
-    while let Some(h) = c2.next() {
-        if h == '\n' {
-            let h2 = string2.parse::<i32>().unwrap();
-            colors.push([
-                (h2 >> 16) as u8,
-                ((h2 >> 8) & 0xff) as u8,
-                (h2 & 0xff) as u8,
-            ]);
-            string2 = String::new();
-        } else {
-            string2 += h.to_string().as_str();
-        }
-    }
-
-    (colors, chars, biggest)
+/// Display width of a single char, 0 for combining marks and unprintables.
+pub fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
 }
+/// Whether `c` takes exactly one column everywhere, including terminals that draw
+/// East Asian ambiguous chars (`¼`, `←`, ...) double-width.
+pub fn is_narrow(c: char) -> bool {
+    c.width() == Some(1) && c.width_cjk() == Some(1)
+}
+/// Ink value in lines.txt of a glyph that covers its whole cell.
+pub const FULL_INK: i32 = 90720;
 impl<C> Frame<C>
 where
     C: Clone + PartialEq + ToAnsi,
 {
+    /// Draws `image` with its top left corner at (x, y), which may be off screen; whatever
+    /// falls outside the frame is clipped. Translucent pixels are blended over the texels
+    /// already there.
     pub fn put_image<ST>(
         &mut self,
-        x: usize,
-        y: usize,
-        s: Size<usize>,
-        imgurl: String,
-        st: ST,
-    ) -> Result<(), ()>
+        x: isize,
+        y: isize,
+        image: &DynamicImage,
+        opts: &ImageOptions,
+        st: &ST,
+    ) -> Result<(), ImageError>
     where
-        C: FromRGB<ST>,
+        C: FromRGBShape<ST> + ToRGB<ST>,
     {
-        let image = ImageReader::open(imgurl).unwrap().decode().unwrap();
-        let (width, height) = s.to_dimensions((
-            (2.5 * image.dimensions().0 as f32) as usize,
-            image.dimensions().1 as usize,
-        ));
-        if x + width as usize <= self.width && y + height as usize <= self.height {
-            let mut text: Vec<Vec<([u32; 4], u32)>> =
-                vec![vec![([0; 4], 0); height as usize]; width as usize];
-            for pix in image.pixels() {
-                let x = ((pix.0 as f32) / (image.width() as f32 / width as f32) as f32) as usize;
-                let y = ((pix.1 as f32) / (image.height() as f32 / height as f32) as f32) as usize;
-                for i in 0..4 {
-                    text[x][y].0[i] += pix.2 .0[i] as u32;
-                }
-                text[x][y].1 += 1;
+        let natural = (
+            ((opts.cell_aspect * image.width() as f32) as usize).max(1),
+            (image.height() as usize).max(1),
+        );
+        let (width, height) = opts.size.to_dimensions(natural);
+        // Fill scales past the box and crops the middle out of the overhang
+        let (scaled, crop) = match opts.size {
+            Size::Fill(..) => {
+                let (sw, sh) = if width * natural.1 >= height * natural.0 {
+                    (width, width * natural.1 / natural.0)
+                } else {
+                    (height * natural.0 / natural.1, height)
+                };
+                let (sw, sh) = (sw.max(width), sh.max(height));
+                ((sw, sh), ((sw - width) / 2, (sh - height) / 2))
             }
-            for y in 0..height {
-                for x in 0..width {
-                    self.set_texel(
-                        x,
-                        y,
-                        C::from_rgb(
-                            (text[x][y].0[0] / text[x][y].1) as u8,
-                            (text[x][y].0[1] / text[x][y].1) as u8,
-                            (text[x][y].0[2] / text[x][y].1) as u8,
-                            &st,
-                        ),
-                    )
-                    .unwrap();
+            _ => ((width, height), (0, 0)),
+        };
+        if width == 0 || height == 0 {
+            return Err(ImageError::Empty);
+        }
+        let g = if opts.shapes { C::grid(st).unwrap_or(1) } else { 1 };
+        let pixels = resample(image, scaled.0 * g, scaled.1 * g, opts.resample);
+
+        let x_from = (-x).max(0) as usize;
+        let y_from = (-y).max(0) as usize;
+        let x_to = width.min((self.width as isize - x).max(0) as usize);
+        let y_to = height.min((self.height as isize - y).max(0) as usize);
+        if x_from >= x_to || y_from >= y_to {
+            return Ok(());
+        }
+        let (w, h) = (x_to - x_from, y_to - y_from);
+        let (fx, fy) = ((x + x_from as isize) as usize, (y + y_from as isize) as usize);
+        let mut rgb = vec![[0u8; 3]; w * h * g * g];
+        let mut transparent = Vec::new();
+        for iy in y_from..y_to {
+            for ix in x_from..x_to {
+                let (tx, ty) = ((x + ix as isize) as usize, (y + iy as isize) as usize);
+                let t = &self.texels[tx + ty * self.width];
+                let under = C::to_rgb(t.0, &t.1, st);
+                let mut opaque = false;
+                for sy in 0..g {
+                    for sx in 0..g {
+                        let p = pixels
+                            .get_pixel(((ix + crop.0) * g + sx) as u32, ((iy + crop.1) * g + sy) as u32)
+                            .0;
+                        opaque |= p[3] > 0;
+                        let a = p[3] as f32 / 255.0;
+                        rgb[((iy - y_from) * g + sy) * w * g + (ix - x_from) * g + sx] = [
+                            (p[0] as f32 * a + under[0] as f32 * (1.0 - a)) as u8,
+                            (p[1] as f32 * a + under[1] as f32 * (1.0 - a)) as u8,
+                            (p[2] as f32 * a + under[2] as f32 * (1.0 - a)) as u8,
+                        ];
+                    }
+                }
+                if !opaque {
+                    transparent.push((tx, ty, self.texel_str(tx, ty), t.1.clone()));
                 }
             }
-            Ok(())
+        }
+        if g > 1 {
+            self.put_rgb_shapes(fx, fy, w, h, g, &rgb, st);
         } else {
-            Err(())
+            self.put_rgb(fx, fy, w, h, &rgb, st, opts.dither);
         }
+        // fully transparent pixels leave the texel alone instead of re-quantising it
+        for (tx, ty, text, c) in transparent {
+            self.put_str(tx, ty, &text, c);
+        }
+        Ok(())
+    }
+    pub fn put_image_file<ST>(
+        &mut self,
+        x: isize,
+        y: isize,
+        path: impl AsRef<Path>,
+        opts: &ImageOptions,
+        st: &ST,
+    ) -> Result<(), ImageError>
+    where
+        C: FromRGBShape<ST> + ToRGB<ST>,
+    {
+        let image = load_image(path)?;
+        self.put_image(x, y, &image, opts, st)
     }
 }
 pub trait ToAnsi {
@@ -177,6 +322,48 @@ pub trait ToAnsi {
 pub trait FromRGB<ST> {
     fn from_rgb(r: u8, g: u8, b: u8, st: &ST) -> (char, Self);
 }
+/// The colour a texel appears as from a distance, the inverse of `FromRGB`.
+pub trait ToRGB<ST> {
+    fn to_rgb(ch: char, c: &Self, st: &ST) -> [u8; 3];
+}
+fn ink(ch: char, st: &TerminalData) -> i32 {
+    st.glyphs.iter().find(|c| c.0 == ch).map(|c| c.1).unwrap_or(0)
+}
+fn mix(fg: [f32; 3], bg: [f32; 3], fgm: f32) -> [u8; 3] {
+    [
+        (fg[0] * fgm + bg[0] * (1.0 - fgm)) as u8,
+        (fg[1] * fgm + bg[1] * (1.0 - fgm)) as u8,
+        (fg[2] * fgm + bg[2] * (1.0 - fgm)) as u8,
+    ]
+}
+impl ToRGB<TerminalData> for () {
+    fn to_rgb(ch: char, _c: &Self, st: &TerminalData) -> [u8; 3] {
+        let max = st.glyphs.iter().map(|c| c.1).max().unwrap_or(1).max(1);
+        let (fg, bg) = st.default_colors();
+        mix(fg.map(|v| v as f32), bg.map(|v| v as f32), ink(ch, st) as f32 / max as f32)
+    }
+}
+impl ToRGB<TerminalData> for u8 {
+    fn to_rgb(ch: char, c: &Self, st: &TerminalData) -> [u8; 3] {
+        let fg = st.palette[(c >> 4) as usize].map(|v| v as f32);
+        let bg = st.palette[(c & 0xf) as usize].map(|v| v as f32);
+        mix(fg, bg, ink(ch, st) as f32 / FULL_INK as f32)
+    }
+}
+/// Approximates the 6x6x6 cube linearly, the same way `FromRGB` for `u16` does.
+fn cube_rgb(i: u16) -> [f32; 3] {
+    let i = i.saturating_sub(16).min(215);
+    [
+        (i / 36) as f32 / 5.0 * 255.0,
+        (i / 6 % 6) as f32 / 5.0 * 255.0,
+        (i % 6) as f32 / 5.0 * 255.0,
+    ]
+}
+impl ToRGB<TerminalData> for u16 {
+    fn to_rgb(ch: char, c: &Self, st: &TerminalData) -> [u8; 3] {
+        mix(cube_rgb(c >> 8), cube_rgb(c & 0xff), ink(ch, st) as f32 / FULL_INK as f32)
+    }
+}
 impl ToAnsi for u8 {
     /*
     0bhccchccc
@@ -207,106 +394,135 @@ impl ToAnsi for (){
 }
 impl FromRGB<TerminalData> for () {
     fn from_rgb(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, Self) {
-        let chars = st.1.clone();
-        let mut best: char = ' ';
-        let mut bs: i32 = i32::MAX;
-        let mut max=0;
-        for &c in &chars {
-            max=max.max(c.1);
-        }
-        let brightness: i32=(((r as f32)+(g as f32)+(b as f32))/(255.0*3.0)*max as f32) as i32;
-        for &c in &chars {
-            let score=(brightness-c.1).abs();
-            if score < bs {
-                (best,bs) = (c.0, score);
-            }
-        }
-        (best,())
+        let lut = st.luts.unit.get_or_init(Lut::default);
+        (lut.get(r, g, b, |r, g, b| (nearest_unit(r, g, b, st), 0)).0, ())
     }
 }
 impl FromRGB<TerminalData> for u8 {
     fn from_rgb(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, Self) {
-        let colors = st.0.clone();
-        let chars = st.1.clone();
-        let mut best: (char, Self) = (' ', 0);
-        let mut score: i32 = i32::MAX;
-        for &c in &chars {
-            let t2 = [r, g, b];
-            let mut i1 = 0;
-            for &co in &colors {
-                let mut i2 = 0;
-                for &co2 in &colors {
-                    let biggest = 90720;
-                    let fgm = c.1 as f32 / biggest as f32;
-                    // let fgm = c.1 as f32 / sa as f32 / 3.0 / 256.0;  //92160
-                    let bgm = 1.0 - fgm;
-                    let mut s = 0;
-                    for i in 0..3 {
-                        let col = (co[i] as f32 * fgm + co2[i] as f32 * bgm) as u8;
-                        let dif = (col as i16 - t2[i] as i16).abs();
-                        s += dif;
-                    }
-                    if (s as i32) < score {
-                        score = s as i32;
-                        best = (c.0, ((i1 << 4) + i2) as u8);
-                    }
-                    i2 += 1;
+        let lut = st.luts.u8.get_or_init(Lut::default);
+        let (c, v) = lut.get(r, g, b, |r, g, b| {
+            let (c, v) = nearest_u8(r, g, b, st);
+            (c, v as u32)
+        });
+        (c, v as u8)
+    }
+}
+impl FromRGB<TerminalData> for u16 {
+    fn from_rgb(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, Self) {
+        let lut = st.luts.u16.get_or_init(Lut::default);
+        let (c, v) = lut.get(r, g, b, |r, g, b| {
+            let (c, v) = nearest_u16(r, g, b, st);
+            (c, v as u32)
+        });
+        (c, v as u16)
+    }
+}
+/// The uncached searches behind `FromRGB`, whose results the LUTs in `TerminalData` keep.
+fn nearest_unit(r: u8, g: u8, b: u8, st: &TerminalData) -> char {
+    let chars = &st.glyphs;
+    let mut best: char = ' ';
+    let mut bs: i32 = i32::MAX;
+    let mut max=0;
+    for &c in chars {
+        max=max.max(c.1);
+    }
+    // how far the colour is from the default background towards the default foreground
+    let (fg, bg) = st.default_colors();
+    let (mut along, mut len) = (0.0, 0.0);
+    for (i, v) in [r, g, b].into_iter().enumerate() {
+        let d = fg[i] as f32 - bg[i] as f32;
+        along += (v as f32 - bg[i] as f32) * d;
+        len += d * d;
+    }
+    let t = if len > 0.0 { (along / len).clamp(0.0, 1.0) } else { 0.0 };
+    let brightness: i32=(t*max as f32) as i32;
+    for &c in chars {
+        let score=(brightness-c.1).abs();
+        if score < bs {
+            (best,bs) = (c.0, score);
+        }
+    }
+    best
+}
+fn nearest_u8(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, u8) {
+    let colors = &st.palette;
+    let chars = &st.glyphs;
+    let mut best: (char, u8) = (' ', 0);
+    let mut score: i32 = i32::MAX;
+    for &c in chars {
+        let t2 = [r, g, b];
+        for (i1, &co) in colors.iter().enumerate() {
+            for (i2, &co2) in colors.iter().enumerate() {
+                let fgm = c.1 as f32 / FULL_INK as f32;
+                // let fgm = c.1 as f32 / sa as f32 / 3.0 / 256.0;  //92160
+                let bgm = 1.0 - fgm;
+                let mut s = 0;
+                for i in 0..3 {
+                    let col = (co[i] as f32 * fgm + co2[i] as f32 * bgm) as u8;
+                    let dif = (col as i16 - t2[i] as i16).abs();
+                    s += dif;
+                }
+                if (s as i32) < score {
+                    score = s as i32;
+                    best = (c.0, ((i1 << 4) + i2) as u8);
                 }
-                i1 += 1;
             }
         }
-        best
     }
+    best
 }
-impl FromRGB<TerminalData> for u16 {
-    fn from_rgb(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, Self) {
-        let chars = st.1.clone();
-        let c1 = [
-            ((r as f32) / 255.0 * 5.0).round() as u8,
-            ((g as f32) / 255.0 * 5.0).round() as u8,
-            ((b as f32) / 255.0 * 5.0).round() as u8,
-        ];
-        let mut bch = [0, 0, 0];
-        let mut score = i16::MAX;
-        for dr in [-1, 1] {
-            for dg in [-1, 1] {
-                for db in [-1, 1] {
-                    let c2 = [
-                        ((c1[0] as i16) + dr),
-                        ((c1[1] as i16) + dg),
-                        ((c1[2] as i16) + db),
-                    ];
-                    if c2[0] >= 0 && c2[0] < 6 && c2[1] >= 0 && c2[1] < 6 && c2[2] >= 0 && c2[2] < 6
-                    {
-                        let mut s = 0;
-                        let dif = ((r as i16) - ((c2[0] as f32)/5.0*255.0) as i16).abs()
-                            + ((g as i16) - ((c2[1] as f32)/5.0*255.0) as i16).abs()
-                            + ((b as i16) - ((c2[2] as f32)/5.0*255.0) as i16).abs();
-                        s += dif;
-                        if s < score {
-                            bch = [c2[0], c2[1], c2[2]];
-                            score = s;
-                        }
+fn nearest_u16(r: u8, g: u8, b: u8, st: &TerminalData) -> (char, u16) {
+    let chars = &st.glyphs;
+    let c1 = [
+        ((r as f32) / 255.0 * 5.0).round() as u8,
+        ((g as f32) / 255.0 * 5.0).round() as u8,
+        ((b as f32) / 255.0 * 5.0).round() as u8,
+    ];
+    let mut bch = [0, 0, 0];
+    let mut score = i16::MAX;
+    for dr in [-1, 1] {
+        for dg in [-1, 1] {
+            for db in [-1, 1] {
+                let c2 = [
+                    ((c1[0] as i16) + dr),
+                    ((c1[1] as i16) + dg),
+                    ((c1[2] as i16) + db),
+                ];
+                if c2[0] >= 0 && c2[0] < 6 && c2[1] >= 0 && c2[1] < 6 && c2[2] >= 0 && c2[2] < 6
+                {
+                    let mut s = 0;
+                    let dif = ((r as i16) - ((c2[0] as f32)/5.0*255.0) as i16).abs()
+                        + ((g as i16) - ((c2[1] as f32)/5.0*255.0) as i16).abs()
+                        + ((b as i16) - ((c2[2] as f32)/5.0*255.0) as i16).abs();
+                    s += dif;
+                    if s < score {
+                        bch = [c2[0], c2[1], c2[2]];
+                        score = s;
                     }
                 }
             }
         }
-        let mut bc: char = ' ';
-        let mut score2=f32::MAX;
-        for &c in &chars {
-            let fg=(c.1 as f32)/ 90720.0;
-            let bg=1.0-fg;
-            let dr=((c1[0] as f32) * bg + (bch[0] as f32) * fg - (r as f32)/255.0*5.0).abs();
-            let dg=((c1[1] as f32) * bg + (bch[1] as f32) * fg - (g as f32)/255.0*5.0).abs();
-            let db=((c1[2] as f32) * bg + (bch[2] as f32) * fg - (b as f32)/255.0*5.0).abs();
-            if (dr+dg+db) < score2{
-                bc=c.0;
-                score2=dr+dg+db;
-            }
+    }
+    let mut bc: char = ' ';
+    let mut score2=f32::MAX;
+    for &c in chars {
+        let fg=(c.1 as f32)/ FULL_INK as f32;
+        let bg=1.0-fg;
+        let dr=((c1[0] as f32) * bg + (bch[0] as f32) * fg - (r as f32)/255.0*5.0).abs();
+        let dg=((c1[1] as f32) * bg + (bch[1] as f32) * fg - (g as f32)/255.0*5.0).abs();
+        let db=((c1[2] as f32) * bg + (bch[2] as f32) * fg - (b as f32)/255.0*5.0).abs();
+        if (dr+dg+db) < score2{
+            bc=c.0;
+            score2=dr+dg+db;
         }
-        (bc,(((bch[0]*36+bch[1]*6+bch[2]+16) as u16)+(((c1[0]*36+c1[1]*6+c1[2]+16) as u16)<<8)))//0bffffffffbbbbbbbb
     }
+    // the glyph's ink is drawn in the foreground, so `bch` goes in the high byte
+    (bc,(((c1[0]*36+c1[1]*6+c1[2]+16) as u16)+(((bch[0]*36+bch[1]*6+bch[2]+16) as u16)<<8)))//0bffffffffbbbbbbbb
 }
+/// Target size of a picture in texels. `Width`/`Height` keep the aspect ratio,
+/// `Both` stretches to exactly that size, `Fit` is the largest size that fits in the box
+/// and `Fill` the smallest that covers it (the overhang gets cropped).
 pub enum Size<N>
 where
     N: Clone,
@@ -314,18 +530,95 @@ where
     Width(N),
     Height(N),
     Both(N, N),
+    Fit(N, N),
+    Fill(N, N),
 }
 impl<N> Size<N>
 where
     N: Clone,
     N: Div<Output = N>,
     N: Mul<Output = N>,
+    N: PartialOrd,
 {
-    fn to_dimensions(&self, d: (N, N)) -> (N, N) {
+    /// The size for content whose natural dimensions are `d`; for `Fill` that's the box itself.
+    pub fn to_dimensions(&self, d: (N, N)) -> (N, N) {
         match self {
             Size::Width(w) => (w.clone(), w.clone() * d.1 / d.0),
             Size::Height(h) => (h.clone() * d.0 / d.1, h.clone()),
-            Size::Both(w, h) => (w.clone(), h.clone()),
+            Size::Both(w, h) | Size::Fill(w, h) => (w.clone(), h.clone()),
+            Size::Fit(w, h) => {
+                let hw = w.clone() * d.1.clone() / d.0.clone();
+                if hw <= *h {
+                    (w.clone(), hw)
+                } else {
+                    (h.clone() * d.0 / d.1, h.clone())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::termdata::load_term_data;
+
+    fn row(f: &Frame<()>, y: usize) -> Vec<String> {
+        (0..f.width).map(|x| f.texel_str(x, y)).collect()
+    }
+
+    #[test]
+    fn put_str_wide() {
+        let mut f = Frame::new(5, 2, ());
+        assert_eq!(f.put_str(0, 0, "\u{4e16}\u{754c}!", ()), 5);
+        assert_eq!(row(&f, 0), ["\u{4e16}", "", "\u{754c}", "", "!"]);
+        // a wide char that doesn't fit in what's left of the row isn't drawn at all
+        assert_eq!(f.put_str(0, 1, "ab\u{4e16}\u{754c}", ()), 4);
+        assert_eq!(row(&f, 1), ["a", "b", "\u{4e16}", "", " "]);
+        assert_eq!(f.put_str(4, 1, "\u{754c}", ()), 0);
+        assert_eq!(f.texel_str(4, 1), " ");
+        // nor is one set in the last column
+        f.set_texel(4, 0, ('\u{754c}', ())).unwrap();
+        assert_eq!(row(&f, 0), ["\u{4e16}", "", "\u{754c}", "", " "]);
+        assert!(!f.render_str().contains(WIDE_TAIL));
+    }
+
+    #[test]
+    fn overwriting_half_of_a_wide_char() {
+        let mut f = Frame::new(4, 1, ());
+        f.put_str(0, 0, "\u{4e16}\u{754c}", ());
+        // its right half: the left one is blanked
+        f.set_texel(1, 0, ('x', ())).unwrap();
+        // its left half: the right one is blanked
+        f.set_texel(2, 0, ('y', ())).unwrap();
+        assert_eq!(row(&f, 0), [" ", "x", "y", " "]);
+        assert_eq!(f.set_texel(4, 0, ('z', ())), Err(OutOfBounds));
+    }
+
+    #[test]
+    fn put_str_clusters() {
+        let mut f = Frame::new(4, 1, ());
+        // e + acute, a + diaeresis, and a flag made of two regional indicators
+        assert_eq!(f.put_str(0, 0, "e\u{301}a\u{308}\u{1f1e9}\u{1f1ea}", ()), 4);
+        assert_eq!(row(&f, 0), ["e\u{301}", "a\u{308}", "\u{1f1e9}\u{1f1ea}", ""]);
+        // a mark with nothing to combine with gets a space
+        let mut f = Frame::new(3, 1, ());
+        assert_eq!(f.put_str(0, 0, "\u{301}x", ()), 2);
+        assert_eq!(row(&f, 0), [" \u{301}", "x", " "]);
+        // overwriting a cluster drops its marks
+        f.set_texel(0, 0, ('o', ())).unwrap();
+        assert_eq!(f.texel_str(0, 0), "o");
+    }
+
+    #[test]
+    fn u16_round_trip() {
+        let st = load_term_data(None).unwrap();
+        for rgb in [[0, 0, 0], [255, 255, 255], [200, 30, 90], [10, 140, 250], [128, 128, 128], [77, 201, 13]] {
+            let (ch, c) = nearest_u16(rgb[0], rgb[1], rgb[2], &st);
+            let back = <u16 as ToRGB<TerminalData>>::to_rgb(ch, &c, &st);
+            // off by at most the glyphs' ink steps, not by the distance between two cube levels
+            let off: i32 = (0..3).map(|i| (back[i] as i32 - rgb[i] as i32).abs()).sum();
+            assert!(off <= 45, "{rgb:?} became {back:?} via {ch:?} {c:#06x}");
         }
     }
 }
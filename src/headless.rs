@@ -0,0 +1,265 @@
+//! Runs apps without a client: output goes into a buffer instead of a channel, at a fixed
+//! terminal size and seed. On a paused tokio clock (`#[tokio::test(start_paused = true)]`)
+//! time only moves when every task is waiting, so a run comes out the same every time.
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::args::{self, Args, ArgsError};
+use crate::config::config;
+use crate::metrics;
+use crate::record::Recorder;
+use crate::scheduler::FrameScheduler;
+use crate::seed;
+use crate::termdata::{init_term_data, read_term_data};
+use crate::vt::Screen;
+use crate::{PtyData, run_app};
+
+pub struct Headless {
+    pub app: &'static str,
+    /// The username help greets.
+    pub user: String,
+    pub cols: u32,
+    pub rows: u32,
+    pub seed: u64,
+    pub args: Args,
+    pub recorder: Option<Arc<Recorder>>,
+}
+impl Headless {
+    pub fn new(app: &'static str, cols: u32, rows: u32, seed: u64) -> Self {
+        let mut args = Args::default();
+        args.set_int("seed", seed);
+        Headless {
+            app,
+            user: app.to_string(),
+            cols,
+            rows,
+            seed,
+            args,
+            recorder: None,
+        }
+    }
+    /// Records the run into `recorder`.
+    pub fn with_recorder(mut self, recorder: Arc<Recorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+    /// Parses `words` as the app's options, like a command would be. A `--seed` among them
+    /// wins over the one given to `new`.
+    pub fn with_args(mut self, words: &[&str]) -> Result<Self, ArgsError> {
+        let words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        let spec = args::spec(self.app).ok_or_else(|| ArgsError::Unknown(self.app.to_string()))?;
+        self.args = args::parse(spec, &words)?;
+        if self.args.int("seed").is_none() {
+            self.args.set_int("seed", self.seed);
+        }
+        Ok(self)
+    }
+    /// Runs the app for `ticks` of the server's frame interval, or until it ends on its own,
+    /// and returns everything it wrote.
+    pub async fn run(&self, ticks: u32) -> Vec<u8> {
+        let capture = Arc::new(StdMutex::new(Vec::new()));
+        let mut sched = FrameScheduler::headless(capture.clone(), metrics::app(self.app));
+        if let Some(r) = &self.recorder {
+            sched.record_to(r.clone());
+        }
+        let duration = sched.interval() * ticks;
+        let data = Arc::new(Mutex::new(PtyData {
+            term: "xterm-256color".to_string(),
+            col_width: self.cols,
+            row_height: self.rows,
+            pix_width: 0,
+            pix_height: 0,
+        }));
+        let app = run_app(self.app, sched, data, read_term_data(), &self.args, &self.user);
+        let _ = timeout(duration, app).await;
+        std::mem::take(&mut *capture.lock().unwrap())
+    }
+    /// What the client's screen shows after `run`.
+    pub async fn screen(&self, ticks: u32) -> Screen {
+        let mut screen = Screen::new(self.cols as usize, self.rows as usize);
+        screen.feed(&self.run(ticks).await);
+        screen
+    }
+}
+
+/// A run asked for on the command line, as `[--out FILE] [--size COLSxROWS] [--seconds N]
+/// <app> [app options]`.
+pub struct Cli {
+    pub run: Headless,
+    pub out: Option<PathBuf>,
+    pub seconds: f64,
+}
+impl Cli {
+    /// Parses `args` and loads the terminal data. Problems are printed, with `usage` where
+    /// that helps, and turned into the exit code.
+    pub fn parse(args: &[String], usage: &str) -> Result<Cli, ExitCode> {
+        let mut out = None;
+        let (mut cols, mut rows) = (80, 24);
+        let mut seconds = 10.0f64;
+        let mut args = args.iter();
+        let app = loop {
+            let Some(a) = args.next() else {
+                eprintln!("{usage}");
+                return Err(ExitCode::FAILURE);
+            };
+            if !a.starts_with('-') {
+                break a;
+            }
+            let value = match a.as_str() {
+                "--out" | "--size" | "--seconds" => match args.next() {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("{a} needs a value\n\n{usage}");
+                        return Err(ExitCode::FAILURE);
+                    }
+                },
+                "-h" | "--help" => {
+                    println!("{usage}");
+                    return Err(ExitCode::SUCCESS);
+                }
+                _ => {
+                    eprintln!("unexpected argument {a}\n\n{usage}");
+                    return Err(ExitCode::FAILURE);
+                }
+            };
+            let ok = match a.as_str() {
+                "--out" => {
+                    out = Some(PathBuf::from(value));
+                    true
+                }
+                "--size" => match value.split_once('x').map(|(c, r)| (c.parse(), r.parse())) {
+                    Some((Ok(c), Ok(r))) if c > 0 && r > 0 => {
+                        (cols, rows) = (c, r);
+                        true
+                    }
+                    _ => false,
+                },
+                _ => value.parse().map(|v| seconds = v).is_ok() && seconds > 0.0,
+            };
+            if !ok {
+                eprintln!("invalid value for {a}: {value}");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+        let Some(spec) = args::spec(app) else {
+            let names: Vec<&str> = args::APPS.iter().map(|a| a.name).collect();
+            eprintln!("{app}: unknown app, try one of {}", names.join(", "));
+            return Err(ExitCode::FAILURE);
+        };
+        let words: Vec<&str> = args.map(String::as_str).collect();
+        let run = match Headless::new(spec.name, cols, rows, seed::configured().unwrap_or_else(seed::random)).with_args(&words) {
+            Ok(run) => run,
+            Err(e) => {
+                eprintln!("{app}: {e}\ntry `{app} --help` for its options");
+                return Err(ExitCode::FAILURE);
+            }
+        };
+        if let Err(e) = init_term_data() {
+            eprintln!("{e}");
+            return Err(ExitCode::FAILURE);
+        }
+        Ok(Cli { run, out, seconds })
+    }
+    /// The seed the app runs with.
+    pub fn seed(&self) -> u64 {
+        self.run.args.int("seed").unwrap_or(self.run.seed)
+    }
+    /// `seconds` in ticks of the server's frame rate.
+    pub fn ticks(&self) -> u32 {
+        (self.seconds * config().fps).ceil() as u32
+    }
+}
+
+/// Runs `future` on a runtime of its own with a paused clock, which skips ahead whenever
+/// every task is waiting. Pausing needs tokio's test clock, which only the `headless`
+/// feature builds in.
+#[cfg(feature = "headless")]
+pub fn block_on_paused<F: Future>(future: F) -> Result<F::Output, ExitCode> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build();
+    match runtime {
+        Ok(r) => Ok(r.block_on(future)),
+        Err(e) => {
+            eprintln!("could not start the runtime: {e}");
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+#[cfg(not(feature = "headless"))]
+pub fn block_on_paused<F: Future>(_future: F) -> Result<F::Output, ExitCode> {
+    eprintln!("this build has no headless runs, rebuild with `cargo build --release --features headless`");
+    Err(ExitCode::FAILURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use super::*;
+    use crate::vt::Color;
+
+    /// Compares `screen` with `tests/golden/<name>.txt`; with `UPDATE_GOLDEN=1` set, writes
+    /// it there instead.
+    fn golden(name: &str, screen: &Screen) {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", &format!("{name}.txt")]
+            .iter()
+            .collect();
+        let text = screen.text();
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &text).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("{}: {e}, run with UPDATE_GOLDEN=1 to create it", path.display()));
+        assert!(
+            text == expected,
+            "{name} differs from {}, run with UPDATE_GOLDEN=1 if that is intended\n--- got:\n{text}--- expected:\n{expected}",
+            path.display()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn weather() {
+        let screen = Headless::new("weather", 60, 20, 47).screen(45).await;
+        assert!(screen.alternate() && !screen.cursor_visible());
+        golden("weather", &screen);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_messages() {
+        let run = Headless::new("virus", 60, 12, 47).with_args(&["--errors"]).unwrap();
+        let screen = run.screen(300).await;
+        golden("virus", &screen);
+        // outcomes are green, or red when they failed
+        let lines = screen.text();
+        for (y, line) in lines.lines().enumerate() {
+            let outcome = [("done!", Color::Indexed(2)), ("failed!", Color::Indexed(1))]
+                .into_iter()
+                .find(|o| line.ends_with(o.0));
+            if let Some((word, colour)) = outcome {
+                let x = line.chars().count() - word.len();
+                assert_eq!(screen.cell(x, y).attrs.fg, colour, "{line}");
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn help() {
+        let mut run = Headless::new("help", 80, 16, 0);
+        run.user = "guest".to_string();
+        let screen = run.screen(10).await;
+        assert!(!screen.alternate());
+        golden("help", &screen);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::dither::Dither;
+use crate::frame::{Frame, FromRGB, OutOfBounds, ToAnsi, ToRGB};
+use crate::shape::FromRGBShape;
+
+/// The fixed back-to-front order scenes are composited in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LayerKind {
+    Sky,
+    Clouds,
+    Ground,
+    Particles,
+    Overlay,
+}
+impl LayerKind {
+    pub const ALL: [LayerKind; 5] = [
+        LayerKind::Sky,
+        LayerKind::Clouds,
+        LayerKind::Ground,
+        LayerKind::Particles,
+        LayerKind::Overlay,
+    ];
+}
+
+/// One RGBA plane (straight alpha, 0..1) with its own depth buffer.
+pub struct Layer {
+    pub width: usize,
+    pub height: usize,
+    pub visible: bool,
+    pub opacity: f32,
+    pub pixels: Vec<[f32; 4]>,
+    pub depth: Vec<f32>,
+}
+impl Layer {
+    pub fn new(width: usize, height: usize) -> Layer {
+        Layer {
+            width,
+            height,
+            visible: true,
+            opacity: 1.0,
+            pixels: vec![[0.0; 4]; width * height],
+            depth: vec![f32::INFINITY; width * height],
+        }
+    }
+    pub fn clear(&mut self) {
+        self.pixels.fill([0.0; 4]);
+        self.depth.fill(f32::INFINITY);
+    }
+    /// Replaces the pixel, ignoring depth.
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3], alpha: f32) -> Result<(), OutOfBounds> {
+        if x < self.width && y < self.height {
+            self.pixels[x + y * self.width] = to_rgba(rgb, alpha);
+            Ok(())
+        } else {
+            Err(OutOfBounds)
+        }
+    }
+    /// Draws `rgb` over whatever the layer already holds at (x, y).
+    pub fn blend_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3], alpha: f32) -> Result<(), OutOfBounds> {
+        if x < self.width && y < self.height {
+            let i = x + y * self.width;
+            self.pixels[i] = over(to_rgba(rgb, alpha), self.pixels[i]);
+            Ok(())
+        } else {
+            Err(OutOfBounds)
+        }
+    }
+    /// Like `set_pixel`, but only if `z` is nearer than what was drawn there before.
+    /// Returns whether the pixel was written.
+    pub fn set_pixel_depth(
+        &mut self,
+        x: usize,
+        y: usize,
+        z: f32,
+        rgb: [u8; 3],
+        alpha: f32,
+    ) -> Result<bool, OutOfBounds> {
+        if x < self.width && y < self.height {
+            let i = x + y * self.width;
+            if z < self.depth[i] {
+                self.depth[i] = z;
+                self.pixels[i] = to_rgba(rgb, alpha);
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        } else {
+            Err(OutOfBounds)
+        }
+    }
+}
+
+pub struct LayerStack {
+    pub width: usize,
+    pub height: usize,
+    pub layers: Vec<(LayerKind, Layer)>,
+}
+impl LayerStack {
+    pub fn new(width: usize, height: usize) -> LayerStack {
+        LayerStack {
+            width,
+            height,
+            layers: LayerKind::ALL
+                .iter()
+                .map(|&k| (k, Layer::new(width, height)))
+                .collect(),
+        }
+    }
+    /// Resizes every layer, dropping their contents; visibility and opacity are kept.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        for (_, l) in &mut self.layers {
+            let mut n = Layer::new(width, height);
+            n.visible = l.visible;
+            n.opacity = l.opacity;
+            *l = n;
+        }
+    }
+    pub fn clear(&mut self) {
+        for (_, l) in &mut self.layers {
+            l.clear();
+        }
+    }
+    pub fn layer(&self, kind: LayerKind) -> &Layer {
+        &self.layers.iter().find(|(k, _)| *k == kind).unwrap().1
+    }
+    pub fn layer_mut(&mut self, kind: LayerKind) -> &mut Layer {
+        &mut self.layers.iter_mut().find(|(k, _)| *k == kind).unwrap().1
+    }
+    pub fn set_visible(&mut self, kind: LayerKind, visible: bool) {
+        self.layer_mut(kind).visible = visible;
+    }
+    /// Flattens the visible layers back to front onto a black background.
+    pub fn composite(&self) -> Vec<[u8; 3]> {
+        let mut out = vec![[0.0f32, 0.0, 0.0, 1.0]; self.width * self.height];
+        for (_, l) in self.layers.iter().filter(|(_, l)| l.visible && l.opacity > 0.0) {
+            for (o, p) in out.iter_mut().zip(&l.pixels) {
+                if p[3] > 0.0 {
+                    *o = over([p[0], p[1], p[2], p[3] * l.opacity], *o);
+                }
+            }
+        }
+        out.iter()
+            .map(|p| {
+                [
+                    (p[0] * 255.0).round() as u8,
+                    (p[1] * 255.0).round() as u8,
+                    (p[2] * 255.0).round() as u8,
+                ]
+            })
+            .collect()
+    }
+    /// Composites and quantises the result into `frame`, clipped to the smaller of the two.
+    pub fn composite_into<C, ST>(&self, frame: &mut Frame<C>, st: &ST, dither: Dither)
+    where
+        C: Clone + PartialEq + ToAnsi + FromRGB<ST> + ToRGB<ST>,
+    {
+        let rgb = self.composite();
+        frame.put_rgb(0, 0, self.width, self.height, &rgb, st, dither);
+    }
+    /// For a stack `grid` times the frame's resolution: shape matches every cell's sub-pixels.
+    pub fn composite_into_shapes<C, ST>(&self, frame: &mut Frame<C>, st: &ST, grid: usize)
+    where
+        C: Clone + PartialEq + ToAnsi + FromRGBShape<ST>,
+    {
+        let rgb = self.composite();
+        frame.put_rgb_shapes(0, 0, self.width / grid, self.height / grid, grid, &rgb, st);
+    }
+}
+
+fn to_rgba(rgb: [u8; 3], alpha: f32) -> [f32; 4] {
+    [
+        rgb[0] as f32 / 255.0,
+        rgb[1] as f32 / 255.0,
+        rgb[2] as f32 / 255.0,
+        alpha.clamp(0.0, 1.0),
+    ]
+}
+/// Porter-Duff "source over destination" for straight alpha.
+fn over(src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+    let a = src[3] + dst[3] * (1.0 - src[3]);
+    if a <= 0.0 {
+        return [0.0; 4];
+    }
+    let mut out = [0.0, 0.0, 0.0, a];
+    for i in 0..3 {
+        out[i] = (src[i] * src[3] + dst[i] * dst[3] * (1.0 - src[3])) / a;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn opaque_over_translucent() {
+        let mut l = Layer::new(2, 1);
+        l.blend_pixel(0, 0, RED, 0.5).unwrap();
+        l.blend_pixel(0, 0, BLUE, 1.0).unwrap();
+        assert_eq!(l.pixels[0], [0.0, 0.0, 1.0, 1.0]);
+        // and the other way round the colours mix, and the alpha adds up
+        l.blend_pixel(1, 0, BLUE, 1.0).unwrap();
+        l.blend_pixel(1, 0, RED, 0.5).unwrap();
+        assert_eq!(l.pixels[1], [0.5, 0.0, 0.5, 1.0]);
+        let mut l = Layer::new(1, 1);
+        l.blend_pixel(0, 0, RED, 0.5).unwrap();
+        l.blend_pixel(0, 0, RED, 0.5).unwrap();
+        assert_eq!(l.pixels[0], [1.0, 0.0, 0.0, 0.75]);
+        assert_eq!(l.blend_pixel(1, 0, RED, 1.0), Err(OutOfBounds));
+    }
+
+    #[test]
+    fn transparent_changes_nothing() {
+        let mut l = Layer::new(1, 1);
+        l.blend_pixel(0, 0, RED, 0.25).unwrap();
+        let before = l.pixels[0];
+        l.blend_pixel(0, 0, BLUE, 0.0).unwrap();
+        assert_eq!(l.pixels[0], before);
+        // nor does it when compositing, even over nothing
+        let mut stack = LayerStack::new(2, 1);
+        stack.layer_mut(LayerKind::Sky).set_pixel(0, 0, RED, 1.0).unwrap();
+        stack.layer_mut(LayerKind::Overlay).set_pixel(0, 0, BLUE, 0.0).unwrap();
+        stack.layer_mut(LayerKind::Overlay).set_pixel(1, 0, BLUE, 0.0).unwrap();
+        assert_eq!(stack.composite(), [RED, [0; 3]]);
+    }
+
+    #[test]
+    fn nearer_depth_wins() {
+        let mut l = Layer::new(1, 1);
+        assert_eq!(l.set_pixel_depth(0, 0, 5.0, RED, 1.0), Ok(true));
+        assert_eq!(l.set_pixel_depth(0, 0, 2.0, BLUE, 1.0), Ok(true));
+        assert_eq!(l.set_pixel_depth(0, 0, 3.0, RED, 1.0), Ok(false));
+        // equally near doesn't replace either
+        assert_eq!(l.set_pixel_depth(0, 0, 2.0, RED, 1.0), Ok(false));
+        assert_eq!(l.pixels[0], [0.0, 0.0, 1.0, 1.0]);
+        l.clear();
+        assert_eq!(l.set_pixel_depth(0, 0, 9.0, RED, 1.0), Ok(true));
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let mut stack = LayerStack::new(1, 1);
+        // depth only counts within a layer: the ground covers the clouds however near they are
+        stack.layer_mut(LayerKind::Ground).set_pixel_depth(0, 0, 100.0, RED, 1.0).unwrap();
+        stack.layer_mut(LayerKind::Clouds).set_pixel_depth(0, 0, 1.0, BLUE, 1.0).unwrap();
+        assert_eq!(stack.composite(), [RED]);
+        // a translucent layer on top tints what is below, its opacity included
+        stack.layer_mut(LayerKind::Overlay).set_pixel(0, 0, [255; 3], 1.0).unwrap();
+        stack.layer_mut(LayerKind::Overlay).opacity = 0.5;
+        assert_eq!(stack.composite(), [[255, 128, 128]]);
+        stack.set_visible(LayerKind::Ground, false);
+        assert_eq!(stack.composite(), [[128, 128, 255]]);
+    }
+}
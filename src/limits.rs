@@ -0,0 +1,208 @@
+//! Admission control: a cap on sessions across the server, and per-IP caps on concurrent
+//! connections and on how fast new ones may be opened. Refused clients are still let in far
+//! enough to be told so, see `Refusal::message`.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::config::config;
+
+/// More rate limit buckets than this and full ones are forgotten.
+const MAX_TRACKED_IPS: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Refusal {
+    /// The server is at `max_sessions`.
+    Full,
+    /// This IP already has `max_connections_per_ip` connections open.
+    TooManyConnections,
+    /// This IP has used up its connection rate.
+    TooFast,
+}
+impl Refusal {
+    pub const ALL: [Refusal; 3] = [Refusal::Full, Refusal::TooManyConnections, Refusal::TooFast];
+    /// Label for logs and metrics.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Refusal::Full => "full",
+            Refusal::TooManyConnections => "too_many_connections",
+            Refusal::TooFast => "too_fast",
+        }
+    }
+    /// What the client is shown before its channel is closed.
+    pub fn message(self) -> &'static str {
+        match self {
+            Refusal::Full => "Sorry, the server is busy right now. Please try again in a few minutes.\n\r",
+            Refusal::TooManyConnections => {
+                "Sorry, there are too many connections from your address already. Close one and try again.\n\r"
+            }
+            Refusal::TooFast => "Sorry, you are connecting too quickly. Please wait a moment and try again.\n\r",
+        }
+    }
+}
+
+/// Token bucket of one IP's new connections.
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let c = config();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * c.connect_rate / 60.0)
+            .min(c.connect_burst);
+        self.last = now;
+    }
+}
+
+/// Who an address counts as: IPv6 clients by their /64, which is what a single customer gets
+/// and can pick addresses from freely, and IPv4 ones mapped into IPv6 as plain IPv4.
+fn client(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6((v6.to_bits() & !0u128 << 64).into()),
+        v4 => v4,
+    }
+}
+
+#[derive(Default)]
+struct PerIp {
+    connections: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+fn per_ip() -> &'static Mutex<PerIp> {
+    static PER_IP: OnceLock<Mutex<PerIp>> = OnceLock::new();
+    PER_IP.get_or_init(Default::default)
+}
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// One admitted connection; counts against its client (see `client`) until dropped.
+pub struct Connection(IpAddr);
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut per_ip = per_ip().lock().unwrap();
+        if let Some(n) = per_ip.connections.get_mut(&self.0) {
+            *n -= 1;
+            if *n == 0 {
+                per_ip.connections.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Decides whether a new connection from `ip` may go ahead. Every attempt costs a token,
+/// refused or not, so retrying in a loop doesn't get through any faster.
+pub fn admit(ip: IpAddr) -> Result<Connection, Refusal> {
+    let ip = client(ip);
+    let c = config();
+    let now = Instant::now();
+    let mut per_ip = per_ip().lock().unwrap();
+    if per_ip.buckets.len() >= MAX_TRACKED_IPS {
+        per_ip.buckets.retain(|_, b| {
+            b.refill(now);
+            b.tokens < c.connect_burst
+        });
+    }
+    let bucket = per_ip.buckets.entry(ip).or_insert(Bucket {
+        tokens: c.connect_burst,
+        last: now,
+    });
+    bucket.refill(now);
+    let allowed = bucket.tokens >= 1.0;
+    bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    if !allowed {
+        return Err(Refusal::TooFast);
+    }
+    if per_ip.connections.get(&ip).copied().unwrap_or(0) >= c.max_connections_per_ip {
+        return Err(Refusal::TooManyConnections);
+    }
+    *per_ip.connections.entry(ip).or_insert(0) += 1;
+    Ok(Connection(ip))
+}
+
+/// One open session channel; counts against `max_sessions` until dropped.
+pub struct SessionSlot(());
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes a session slot, unless the server is full.
+pub fn open_session() -> Result<SessionSlot, Refusal> {
+    let max = config().max_sessions;
+    SESSIONS
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < max).then_some(n + 1))
+        .map(|_| SessionSlot(()))
+        .map_err(|_| Refusal::Full)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn clients() {
+        assert_eq!(client(ip("2001:db8:1:2:3:4:5:6")), ip("2001:db8:1:2::"));
+        assert_eq!(client(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(client(ip("192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let c = config();
+        let start = Instant::now();
+        let mut b = Bucket { tokens: 0.0, last: start };
+        b.refill(start + Duration::from_secs(3));
+        assert!((b.tokens - 3.0 * c.connect_rate / 60.0).abs() < 1e-9);
+        b.refill(start + Duration::from_secs(3600));
+        assert_eq!(b.tokens, c.connect_burst);
+    }
+
+    #[test]
+    fn admission() {
+        let c = config();
+        // a /64 of its own, so other tests don't share its counts
+        let held: Vec<Connection> = (0..c.max_connections_per_ip)
+            .map(|i| admit(ip(&format!("2001:db8:42::{}", i + 1))).unwrap())
+            .collect();
+        // other addresses in the same /64 are the same client
+        assert_eq!(admit(ip("2001:db8:42::ffff")).err(), Some(Refusal::TooManyConnections));
+        assert!(admit(ip("2001:db8:43::1")).is_ok());
+        drop(held);
+        // closing them frees the connections, but the attempts used up the burst
+        let mut last = Ok(());
+        for _ in 0..c.connect_burst as usize {
+            last = admit(ip("2001:db8:42::1")).map(drop);
+            if last.is_err() {
+                break;
+            }
+        }
+        assert_eq!(last, Err(Refusal::TooFast));
+        assert!(!per_ip().lock().unwrap().connections.contains_key(&ip("2001:db8:42::")));
+    }
+
+    #[test]
+    fn session_slots_are_released() {
+        let max = config().max_sessions;
+        let mut slots: Vec<SessionSlot> = (0..max).map(|_| open_session().unwrap()).collect();
+        assert_eq!(open_session().err(), Some(Refusal::Full));
+        slots.pop();
+        slots.push(open_session().unwrap());
+        drop(slots);
+        assert_eq!(SESSIONS.load(Ordering::Relaxed), 0);
+        assert!(open_session().is_ok());
+    }
+}
@@ -0,0 +1,365 @@
+//! Structured logging to stderr, as text or JSON lines. Events carry key/value fields, and a
+//! `SessionSpan` adds the fields of one session to everything logged about it.
+//!
+//! Client IPs and usernames are only logged when `WEATHER_SSH_LOG_IDENTITY` is set, since
+//! `help` tells users their name won't be.
+
+use std::{
+    fmt::{self, Write as _},
+    io::Write as _,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::config;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+impl FromStr for Level {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" | "trace" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    /// `2026-01-02T03:04:05.678Z INFO message key=value ...`
+    Text,
+    /// One JSON object per line with `ts`, `level`, `msg` and the fields.
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A field value; numbers stay numbers in JSON.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Str(String),
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::Float(v) => write!(f, "{v}"),
+            Value::Bool(v) => write!(f, "{v}"),
+        }
+    }
+}
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Str(v.to_string())
+    }
+}
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Str(v)
+    }
+}
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Int(v)
+    }
+}
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Value::Int(v as u64)
+    }
+}
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Value::Int(v as u64)
+    }
+}
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+/// In whole milliseconds.
+impl From<Duration> for Value {
+    fn from(v: Duration) -> Self {
+        Value::Int(v.as_millis() as u64)
+    }
+}
+impl From<IpAddr> for Value {
+    fn from(v: IpAddr) -> Self {
+        Value::Str(v.to_string())
+    }
+}
+
+pub type Fields = Vec<(&'static str, Value)>;
+
+/// Never in tests, which run apps whose events would end up between the test results.
+pub fn enabled(level: Level) -> bool {
+    !cfg!(test) && level <= config().log_level
+}
+
+/// Logs `message` with `fields`, if `level` is enabled.
+pub fn event(level: Level, message: &str, fields: &[(&str, Value)]) {
+    if !enabled(level) {
+        return;
+    }
+    let line = match config().log_format {
+        LogFormat::Text => text_line(level, message, fields),
+        LogFormat::Json => json_line(level, message, fields),
+    };
+    // a failed write to stderr has nowhere to be reported
+    let _ = writeln!(std::io::stderr().lock(), "{line}");
+}
+
+fn text_line(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = format!("{} {:5} ", timestamp(), level.name().to_ascii_uppercase());
+    // messages are meant to have spaces, but those forwarded from the `log` crate may carry
+    // client input too
+    if message.contains(char::is_control) {
+        let _ = write!(line, "{message:?}");
+    } else {
+        line.push_str(message);
+    }
+    for (k, v) in fields {
+        let v = v.to_string();
+        // usernames and terms come from clients: quoting escapes any control characters in
+        // them, so they can't move the cursor or fake a line of their own
+        if v.is_empty() || v.contains(|c: char| c.is_whitespace() || c.is_control() || matches!(c, '"' | '=' | '\\')) {
+            let _ = write!(line, " {k}={v:?}");
+        } else {
+            let _ = write!(line, " {k}={v}");
+        }
+    }
+    line
+}
+fn json_line(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = format!(
+        "{{\"ts\":\"{}\",\"level\":\"{}\",\"msg\":{}",
+        timestamp(),
+        level.name(),
+        json_str(message)
+    );
+    for (k, v) in fields {
+        let v = match v {
+            Value::Str(s) => json_str(s),
+            Value::Float(f) if !f.is_finite() => "null".to_string(),
+            v => v.to_string(),
+        };
+        let _ = write!(line, ",{}:{v}", json_str(k));
+    }
+    line.push('}');
+    line
+}
+/// `s` as a quoted JSON string.
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // C1 controls too, a terminal showing the log would act on them
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Current UTC time as RFC 3339 with milliseconds.
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    let (year, month, day) = civil_date(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+/// (year, month, day) of the day `days` after 1970-01-01, by Howard Hinnant's algorithm.
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The client's IP and username (if known yet) as fields, or nothing unless identity logging is on.
+pub fn identity(ip: IpAddr, user: &str) -> Fields {
+    let mut fields = Vec::new();
+    if config().log_identity {
+        fields.push(("ip", ip.into()));
+        if !user.is_empty() {
+            fields.push(("user", user.into()));
+        }
+    }
+    fields
+}
+
+/// One SSH session channel. Its fields are added to every event logged through it, and
+/// dropping it logs how long the session lasted and how much was sent.
+pub struct SessionSpan {
+    fields: Fields,
+    started: Instant,
+    sent: Arc<AtomicU64>,
+}
+impl SessionSpan {
+    pub fn new(id: u64, identity: Fields) -> Self {
+        let mut fields = vec![("session", id.into())];
+        fields.extend(identity);
+        let span = SessionSpan {
+            fields,
+            started: Instant::now(),
+            sent: Arc::default(),
+        };
+        span.event(Level::Info, "session opened", &[]);
+        span
+    }
+    /// Adds or replaces a field for all later events.
+    pub fn record(&mut self, key: &'static str, value: impl Into<Value>) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|f| f.0 == key) {
+            Some(f) => f.1 = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+    /// Counter for the bytes sent to this session.
+    pub fn sent(&self) -> Arc<AtomicU64> {
+        self.sent.clone()
+    }
+    pub fn event(&self, level: Level, message: &str, fields: &[(&str, Value)]) {
+        if !enabled(level) {
+            return;
+        }
+        let mut all: Vec<(&str, Value)> = self.fields.iter().map(|(k, v)| (*k, v.clone())).collect();
+        all.extend(fields.iter().cloned());
+        event(level, message, &all);
+    }
+}
+impl Drop for SessionSpan {
+    fn drop(&mut self) {
+        self.event(
+            Level::Info,
+            "session closed",
+            &[
+                ("duration_ms", self.started.elapsed().into()),
+                ("bytes_sent", self.sent.load(Ordering::Relaxed).into()),
+            ],
+        );
+    }
+}
+
+/// Routes records from dependencies (russh, ...) that use the `log` crate into the same output.
+struct Bridge;
+impl log::Log for Bridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(from_log(metadata.level()))
+    }
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            event(
+                from_log(record.level()),
+                &record.args().to_string(),
+                &[("target", record.target().into())],
+            );
+        }
+    }
+    fn flush(&self) {}
+}
+fn from_log(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn => Level::Warn,
+        log::Level::Info => Level::Info,
+        log::Level::Debug | log::Level::Trace => Level::Debug,
+    }
+}
+
+/// Installs the `log` bridge; call once at startup.
+pub fn init() {
+    static BRIDGE: Bridge = Bridge;
+    if log::set_logger(&BRIDGE).is_ok() {
+        log::set_max_level(match config().log_level {
+            Level::Error => log::LevelFilter::Error,
+            Level::Warn => log::LevelFilter::Warn,
+            Level::Info => log::LevelFilter::Info,
+            Level::Debug => log::LevelFilter::Debug,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_strings_are_escaped() {
+        let fields = [("user", Value::from("\x1b[2J\x1b]0;pwned\x07")), ("term", Value::from("a\u{9b}31m\nb"))];
+        let line = text_line(Level::Info, "hi", &fields);
+        assert!(line.ends_with(r#" hi user="\u{1b}[2J\u{1b}]0;pwned\u{7}" term="a\u{9b}31m\nb""#), "{line}");
+        assert!(text_line(Level::Info, "hi", &[("user", "a\\b".into())]).ends_with(r#" user="a\\b""#));
+        assert!(text_line(Level::Info, "hi", &[("user", "guest".into())]).ends_with(" user=guest"));
+        // messages are only quoted when they'd need escaping
+        let line = text_line(Level::Warn, "bad packet from \x1b]0;x\x07\r\nFAKE", &[]);
+        assert!(line.ends_with(r#" WARN  "bad packet from \u{1b}]0;x\u{7}\r\nFAKE""#), "{line}");
+        assert!(text_line(Level::Info, "session opened", &[]).ends_with(" INFO  session opened"));
+        assert_eq!(json_str("\x1b\u{9b}\x7f\u{e9}"), "\"\\u001b\\u009b\\u007f\u{e9}\"");
+    }
+}
@@ -0,0 +1,98 @@
+//! Cached `FromRGB` results, so converting a pixel is a table lookup instead of a search over
+//! every glyph and colour pair.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Bits kept per channel; 64³ buckets, each 4 values wide.
+const BITS: u32 = 6;
+const EMPTY: u64 = u64::MAX;
+
+/// A texel per RGB bucket, filled in the first time a colour in the bucket is converted.
+pub struct Lut {
+    cells: Box<[AtomicU64]>,
+}
+impl Default for Lut {
+    fn default() -> Self {
+        Lut {
+            cells: (0..1usize << (3 * BITS)).map(|_| AtomicU64::new(EMPTY)).collect(),
+        }
+    }
+}
+impl Lut {
+    /// The texel for `r`,`g`,`b`, computing it with `f` on a miss. `f` is given the centre of the
+    /// bucket rather than the colour itself, so the table doesn't depend on which colour came first.
+    pub fn get(&self, r: u8, g: u8, b: u8, f: impl FnOnce(u8, u8, u8) -> (char, u32)) -> (char, u32) {
+        let shift = 8 - BITS;
+        let i = ((r >> shift) as usize) << (2 * BITS) | ((g >> shift) as usize) << BITS | (b >> shift) as usize;
+        let cell = self.cells[i].load(Ordering::Relaxed);
+        if cell != EMPTY {
+            // only ever stored from a valid char below
+            return (char::from_u32((cell >> 32) as u32).unwrap(), cell as u32);
+        }
+        let centre = |v: u8| (v >> shift << shift) | (1 << (shift - 1));
+        let (c, v) = f(centre(r), centre(g), centre(b));
+        // racing threads compute the same value, so whichever store lands is fine
+        self.cells[i].store((c as u64) << 32 | v as u64, Ordering::Relaxed);
+        (c, v)
+    }
+}
+
+/// One lazily allocated table per texel colour type, for one set of colours and glyphs.
+#[derive(Default)]
+pub struct Luts {
+    pub unit: OnceLock<Lut>,
+    pub u8: OnceLock<Lut>,
+    pub u16: OnceLock<Lut>,
+}
+
+/// What conversions depend on: the palette, the default foreground and background, and a hash
+/// of the glyph table.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct LutKey {
+    pub palette: Vec<[u8; 3]>,
+    pub foreground: Option<[u8; 3]>,
+    pub background: Option<[u8; 3]>,
+    pub glyphs: u64,
+}
+
+/// The tables for `key`, shared with every `TerminalData` that has the same colours and
+/// glyphs. They're kept only while something uses them, since clients choose their palettes.
+pub fn shared(key: LutKey) -> Arc<Luts> {
+    static SHARED: OnceLock<Mutex<HashMap<LutKey, Weak<Luts>>>> = OnceLock::new();
+    let mut shared = SHARED.get_or_init(Default::default).lock().unwrap();
+    if let Some(luts) = shared.get(&key).and_then(Weak::upgrade) {
+        return luts;
+    }
+    shared.retain(|_, l| l.strong_count() > 0);
+    let luts = Arc::new(Luts::default());
+    shared.insert(key, Arc::downgrade(&luts));
+    luts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_per_key() {
+        let key = |background| LutKey {
+            palette: vec![[0; 3]; 16],
+            foreground: None,
+            background,
+            glyphs: 1,
+        };
+        let a = shared(key(None));
+        assert!(Arc::ptr_eq(&a, &shared(key(None))));
+        assert!(!Arc::ptr_eq(&a, &shared(key(Some([1, 2, 3])))));
+        // only kept while in use
+        let weak = Arc::downgrade(&a);
+        drop(a);
+        assert!(weak.upgrade().is_none());
+    }
+}
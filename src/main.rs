@@ -1,30 +1,90 @@
+pub mod args;
+pub mod auth;
+pub mod calibrate;
+pub mod config;
+pub mod dither;
 pub mod frame;
+pub mod headless;
+pub mod layer;
+pub mod limits;
+pub mod logging;
+pub mod lut;
 pub mod messages;
+pub mod metrics;
+pub mod osc;
+pub mod picture;
+pub mod player;
+pub mod raster;
+pub mod record;
+pub mod report;
+pub mod scheduler;
+pub mod screenshot;
+pub mod seed;
+pub mod shape;
+pub mod shutdown;
+pub mod termdata;
 pub mod vec3;
+pub mod vt;
+pub mod world;
 
-use std::mem;
+use std::env;
+use std::future::Future;
 use std::net::IpAddr;
-use std::ops::{Add, Mul, Neg, Sub};
+use std::pin::Pin;
+use std::process::ExitCode;
 use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
 use std::time::Duration;
 
-use noise::{NoiseFn, Simplex};
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng, rng};
+use noise::NoiseFn;
+use rand::Rng;
 use rand_core::OsRng;
 use russh::server::{Handle, Msg, Server as _, Session};
 use russh::*;
 use tokio::net::TcpListener;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, RwLock, mpsc};
 use tokio::task::JoinHandle;
 use tokio::time::{Instant, sleep};
 
-use crate::frame::{Frame, read_term_data};
-use crate::messages::generate_message;
-use crate::vec3::Vec3;
+use crate::auth::{Requirement, policy};
+use crate::dither::Dither;
+use crate::frame::Frame;
+use crate::layer::{LayerKind, LayerStack};
+use crate::limits::{Refusal, SessionSlot};
+use crate::logging::{Level, SessionSpan, identity};
+use crate::messages::{FAILURE_CHANCE, generate_message};
+use crate::raster::Camera;
+use crate::scheduler::{ClientWindow, FrameScheduler, Quality};
+use crate::seed::SeedRng;
+use crate::shape::shape_grid;
+use crate::shutdown::{LiveSession, restore_sequence};
+use crate::termdata::{TerminalData, init_term_data, read_term_data};
+use crate::world::GROUND_Y;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("calibrate") => calibrate::run(&args[2..]),
+        Some("hash-password") => auth::hash_password(&args[2..]),
+        // these two run on a runtime of their own, with a paused clock (`--features headless`)
+        Some("record") => record::run(&args[2..]),
+        Some("screenshot") => screenshot::run(&args[2..]),
+        _ => serve(),
+    }
+}
 
 #[tokio::main]
-async fn main() {
+async fn serve() -> ExitCode {
+    logging::init();
+    if let Err(e) = init_term_data() {
+        logging::event(Level::Error, "could not load terminal data", &[("error", e.to_string().into())]);
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = auth::init_policy() {
+        logging::event(Level::Error, "could not load auth policy", &[("error", e.to_string().into())]);
+        return ExitCode::FAILURE;
+    }
+    dither::init();
     let config = russh::server::Config {
         inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
         auth_rejection_time: std::time::Duration::from_secs(3),
@@ -35,13 +95,37 @@ async fn main() {
         ..Default::default()
     };
     let config = Arc::new(config);
+    if let Some(addr) = config::config().metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                logging::event(Level::Error, "could not serve metrics", &[("error", e.to_string().into())]);
+            }
+        });
+    }
     let mut sh = SshClientManager::new();
 
     let socket = TcpListener::bind(("0.0.0.0", 2222)).await.unwrap();
     let server = sh.run_on_socket(config, &socket);
-    server.await.unwrap()
+    let server_handle = server.handle();
+    logging::event(Level::Info, "listening", &[("port", 2222u32.into())]);
+    // dropping `server` when the signal arrives stops accepting connections
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = shutdown::wait_for_signal() => {
+            logging::event(Level::Info, "shutting down", &[]);
+            let settings = config::config();
+            shutdown::drain(settings.goodbye.as_deref(), settings.shutdown_timeout).await;
+            server_handle.shutdown("server shutting down".to_string());
+            // give the connections a moment to write out the close and disconnect
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+    ExitCode::SUCCESS
 }
 
+/// How long a refused connection has to open a channel and read why, before it's dropped.
+const REFUSED_GRACE: Duration = Duration::from_secs(5);
+
 struct SshClientManager {}
 impl SshClientManager {
     pub fn new() -> Self {
@@ -53,30 +137,182 @@ struct SshClientHandler {
     sessions: Vec<SessionHandlerWrapper>,
     ip: IpAddr,
     user: String,
+    /// Whether the limits let this connection in. Refused ones still get through auth so
+    /// they can be told why on their first channel.
+    admission: Result<limits::Connection, Refusal>,
+    /// Channels opened only to be told why they're refused; they count against `max_channels` too.
+    refused_channels: usize,
+}
+impl Drop for SshClientHandler {
+    fn drop(&mut self) {
+        for s in &self.sessions {
+            shutdown::unregister(s.data.id);
+        }
+    }
 }
 struct SessionHandlerWrapper {
     session_handler: SessionHandler,
     data: SessionData,
+    span: SessionSpan,
+    /// Counts against `max_sessions` while the channel is open.
+    _slot: SessionSlot,
 }
 #[derive(Clone)]
 struct SessionData {
+    /// Key in the shutdown registry.
+    id: u64,
     user: String,
     chanel_id: ChannelId,
-    ip: IpAddr,
+    /// What runs on the channel, decided by the shell or exec request, and its options.
+    app: &'static str,
+    args: args::Args,
     exit_window: Arc<RwLock<bool>>,
+    window: Arc<ClientWindow>,
+    /// Bytes sent to the channel, reported when the session's span ends.
+    sent: Arc<AtomicU64>,
+    /// Set for terminal sessions picked for recording.
+    recorder: Option<Arc<record::Recorder>>,
 }
 enum SessionHandler {
+    /// Nothing runs until the client asks for a shell or a command; holds the terminal
+    /// if one was requested first.
+    Pending(Option<PtyData>),
     NonPty(NonPtyHandler),
     Pty(PtyHandler),
 }
 impl SshClientHandler {
-    pub fn new(ip: IpAddr) -> Self {
+    pub fn new(ip: IpAddr, admission: Result<limits::Connection, Refusal>) -> Self {
         SshClientHandler {
             sessions: Vec::new(),
             ip,
             user: String::new(),
+            admission,
+            refused_channels: 0,
+        }
+    }
+    /// Whether the policy lets this client use `method` for `user`'s app.
+    fn permitted(&self, user: &str, method: MethodKind) -> bool {
+        matches!(policy().requirement(self.ip, app_name(user)), Requirement::Auth(m) if m.contains(&method))
+    }
+    fn accept(&mut self, user: &str, method: &'static str) -> server::Auth {
+        self.user = user.to_string();
+        metrics::metrics().auth_accepted.inc();
+        let mut fields = identity(self.ip, user);
+        fields.extend([("app", app_name(user).into()), ("method", method.into())]);
+        logging::event(Level::Debug, "authenticated", &fields);
+        server::Auth::Accept
+    }
+    fn reject(&mut self, user: &str, method: &'static str) -> server::Auth {
+        metrics::metrics().auth_rejected.inc();
+        let mut fields = identity(self.ip, user);
+        fields.extend([("app", app_name(user).into()), ("method", method.into())]);
+        logging::event(Level::Info, "authentication rejected", &fields);
+        self.remaining(user)
+    }
+    /// Tells the client what could still work, or that nothing will.
+    fn remaining(&self, user: &str) -> server::Auth {
+        let methods = match policy().requirement(self.ip, app_name(user)) {
+            Requirement::Auth(methods) => MethodSet::from(&methods[..]),
+            _ => MethodSet::empty(),
+        };
+        server::Auth::Reject {
+            proceed_with_methods: Some(methods),
+            partial_success: false,
         }
     }
+    /// Whether this client may run `app`: it's what they logged in for, or it's public.
+    fn may_run(&self, app: &str) -> bool {
+        app == app_name(&self.user) || policy().requirement(self.ip, app) == Requirement::None
+    }
+    /// Starts the app for a shell request, or for an exec request's `command`: interactively
+    /// if the client asked for a terminal, as a one-shot report if not.
+    fn start(&mut self, channel: ChannelId, command: Option<&str>, session: &mut Session) -> Result<(), russh::Error> {
+        let words: Vec<String> = command.unwrap_or("").split_whitespace().map(str::to_string).collect();
+        let app = match words.first() {
+            Some(name) => args::spec(name),
+            None => args::spec(app_name(&self.user)),
+        };
+        let allowed = app.is_some_and(|app| self.may_run(app.name));
+        let user = self.user.clone();
+        let Some(wrapper) = self.sessions.iter_mut().find(|x| x.data.chanel_id == channel) else {
+            // refused channels have no handler
+            return Ok(());
+        };
+        let SessionHandler::Pending(pty) = &mut wrapper.session_handler else {
+            // only one shell or command per channel
+            return session.channel_failure(channel);
+        };
+        let pty = pty.take();
+        session.channel_success(channel)?;
+        let handle = session.handle();
+        // a terminal needs \r to go back to the start of the line
+        let newlines = if pty.is_some() { "\r\n" } else { "\n" };
+        let finish = |text: String, stream: u32, status: u32| -> Pin<Box<dyn Future<Output = u32> + Send>> {
+            let text = CryptoVec::from(text.replace('\n', newlines));
+            let handle = handle.clone();
+            Box::pin(async move {
+                let _ = match stream {
+                    0 => handle.data(channel, text).await,
+                    _ => handle.extended_data(channel, stream, text).await,
+                };
+                status
+            })
+        };
+        let job = match app {
+            None => {
+                let names: Vec<&str> = args::APPS.iter().map(|a| a.name).collect();
+                finish(format!("{}: unknown command, try one of {}\n", words[0], names.join(", ")), 1, 127)
+            }
+            Some(app) if !allowed => {
+                finish(format!("{0}: permission denied, log in as {0}@ to authenticate\n", app.name), 1, 1)
+            }
+            Some(app) => match args::parse(app, words.get(1..).unwrap_or_default()) {
+                Err(e) => finish(format!("{}: {e}\ntry `{} --help`\n", app.name, app.name), 1, 2),
+                Ok(a) if a.flag("help") => finish(app.help(), 0, 0),
+                Ok(mut a) => {
+                    let app = app.name;
+                    // a session's own randomness is always seeded, and the seed logged, so it can
+                    // be replayed; weather scenes are shared, and pick and log theirs when they start
+                    match a.int("seed").or_else(seed::configured) {
+                        Some(seed) => a.set_int("seed", seed),
+                        None if app != "weather" => a.set_int("seed", seed::random()),
+                        None => {}
+                    }
+                    if let Some(seed) = a.int("seed") {
+                        wrapper.span.record("seed", seed);
+                    }
+                    wrapper.data.args = a;
+                    wrapper.data.app = app;
+                    wrapper.span.record("app", app);
+                    wrapper.span.event(
+                        Level::Info,
+                        "app started",
+                        &[("mode", if pty.is_some() { "pty" } else { "exec" }.into())],
+                    );
+                    if let Some(pty) = pty {
+                        wrapper.data.recorder = record::for_session(
+                            wrapper.data.id,
+                            app,
+                            wrapper.data.args.int("seed"),
+                            &pty.term,
+                            pty.col_width,
+                            pty.row_height,
+                        );
+                        wrapper.session_handler =
+                            SessionHandler::Pty(PtyHandler::new(pty, session.handle(), wrapper.data.clone()));
+                        return Ok(());
+                    }
+                    let args = wrapper.data.args.clone();
+                    Box::pin(async move {
+                        let _active = metrics::ActiveApp::new(metrics::app(app));
+                        report::exec(&handle, channel, app, &args, help_text(&user)).await
+                    })
+                }
+            },
+        };
+        wrapper.session_handler = SessionHandler::NonPty(NonPtyHandler::new(session.handle(), wrapper.data.clone(), job));
+        Ok(())
+    }
 }
 
 struct PtyData {
@@ -85,16 +321,22 @@ struct PtyData {
     row_height: u32,
     pix_width: u32,
     pix_height: u32,
-    modes: Vec<(Pty, u32)>,
 }
 
 impl server::Server for SshClientManager {
     type Handler = SshClientHandler;
     fn new_client(&mut self, addr: Option<std::net::SocketAddr>) -> SshClientHandler {
-        SshClientHandler::new(addr.unwrap().ip())
+        let ip=addr.unwrap().ip();
+        logging::event(Level::Info, "client connected", &identity(ip, ""));
+        metrics::metrics().connections.inc();
+        let admission=limits::admit(ip);
+        if let Err(r)=admission{
+            refused(r, ip);
+        }
+        SshClientHandler::new(ip, admission)
     }
     fn handle_session_error(&mut self, _error: <Self::Handler as russh::server::Handler>::Error) {
-        eprintln!("Session error: {_error:#?}");
+        logging::event(Level::Warn, "session error", &[("error", _error.to_string().into())]);
     }
 }
 
@@ -106,23 +348,122 @@ impl server::Handler for SshClientHandler {
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        if self.sessions.len()+self.refused_channels>=config::config().max_channels{
+            logging::event(Level::Warn, "too many channels on one connection", &identity(self.ip, &self.user));
+            return Ok(false);
+        }
+        let slot=match &self.admission{
+            Ok(_)=>limits::open_session().inspect_err(|r| refused(*r, self.ip)),
+            Err(r)=>Err(*r),
+        };
+        let slot=match slot{
+            Ok(slot)=>slot,
+            Err(r)=>{
+                // accept the channel anyway, it's the only way to tell the client why it's closed
+                self.refused_channels+=1;
+                let session_handle=session.handle();
+                let channel=channel.id();
+                // a refused connection holds no `limits::Connection`, so it mustn't stay open
+                let disconnect=self.admission.is_err();
+                tokio::spawn(async move {
+                    let _=session_handle.data(channel, CryptoVec::from(r.message())).await;
+                    sleep(Duration::from_millis(1)).await;
+                    let _=session_handle.close(channel).await;
+                    if disconnect{
+                        let reason=match r{
+                            Refusal::Full=>Disconnect::ServiceNotAvailable,
+                            Refusal::TooManyConnections|Refusal::TooFast=>Disconnect::TooManyConnections,
+                        };
+                        let _=session_handle.disconnect(reason, r.message().trim_end().to_string(), String::new()).await;
+                    }
+                });
+                return Ok(true);
+            }
+        };
+        let exit_window=Arc::new(RwLock::new(false));
+        let id=shutdown::register(LiveSession {
+            handle: session.handle(),
+            channel: channel.id(),
+            exit_window: exit_window.clone(),
+            app: None,
+            pty: false,
+        });
+        let span=SessionSpan::new(id, identity(self.ip, &self.user));
         self.sessions.push(SessionHandlerWrapper {
-            session_handler: SessionHandler::NonPty(NonPtyHandler::new(
-                session.handle(),
-                channel.id(),
-            )),
+            session_handler: SessionHandler::Pending(None),
             data: SessionData {
+                id,
                 chanel_id: channel.id(),
                 user: self.user.clone(),
-                ip: self.ip.clone(),
-                exit_window:Arc::new(RwLock::new(false))
+                app: app_name(&self.user),
+                args: args::Args::default(),
+                exit_window,
+                window: Arc::default(),
+                sent: span.sent(),
+                recorder: None,
             },
+            span,
+            _slot: slot,
         });
         Ok(true)
     }
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        if let Err(r)=self.admission{
+            // a client that never opens a channel to hear why still doesn't get to stay
+            let session_handle=session.handle();
+            tokio::spawn(async move {
+                sleep(REFUSED_GRACE).await;
+                let _=session_handle.disconnect(Disconnect::ByApplication, r.message().trim_end().to_string(), String::new()).await;
+            });
+        }
+        Ok(())
+    }
     async fn auth_none(&mut self, user: &str) -> Result<server::Auth, Self::Error> {
-        self.user = user.to_string();
-        Ok(server::Auth::Accept)
+        match policy().requirement(self.ip, app_name(user)) {
+            Requirement::None => Ok(self.accept(user, "none")),
+            Requirement::Auth(methods) => Ok(server::Auth::Reject {
+                proceed_with_methods: Some(MethodSet::from(&methods[..])),
+                partial_success: false,
+            }),
+            Requirement::Denied => Ok(self.reject(user, "address")),
+        }
+    }
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &keys::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        // only says whether the key is worth proving ownership of, auth_publickey decides.
+        // clients offer every key they have, so a miss here isn't a failed login
+        if self.permitted(user, MethodKind::PublicKey) && policy().key_allowed(app_name(user), self.ip, public_key) {
+            Ok(server::Auth::Accept)
+        } else {
+            Ok(self.remaining(user))
+        }
+    }
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &keys::PublicKey,
+    ) -> Result<server::Auth, Self::Error> {
+        if self.permitted(user, MethodKind::PublicKey) && policy().key_allowed(app_name(user), self.ip, public_key) {
+            Ok(self.accept(user, "publickey"))
+        } else {
+            Ok(self.reject(user, "publickey"))
+        }
+    }
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<server::Auth, Self::Error> {
+        let ok = self.permitted(user, MethodKind::Password) && {
+            let (app, password) = (app_name(user), password.to_string());
+            tokio::task::spawn_blocking(move || policy().password_ok(app, &password))
+                .await
+                .unwrap_or(false)
+        };
+        if ok {
+            Ok(self.accept(user, "password"))
+        } else {
+            Ok(self.reject(user, "password"))
+        }
     }
     async fn pty_request(
         &mut self,
@@ -132,38 +473,58 @@ impl server::Handler for SshClientHandler {
         row_height: u32,
         pix_width: u32,
         pix_height: u32,
-        modes: &[(Pty, u32)],
+        _modes: &[(Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         session.channel_success(channel)?;
-        let session_handler_wrapper = self
+        // refused channels have no handler
+        let Some(session_handler_wrapper) = self
             .sessions
             .iter_mut()
             .find(|x| x.data.chanel_id == channel)
-            .unwrap();
+        else {
+            return Ok(());
+        };
+        let span=&mut session_handler_wrapper.span;
+        span.record("term", term);
+        span.record("cols", col_width);
+        span.record("rows", row_height);
+        span.event(Level::Info, "pty requested", &[]);
         let pty_data = PtyData {
             term: term.to_string(),
             col_width,
             row_height,
             pix_width,
             pix_height,
-            modes: modes.to_vec(),
         };
-        match &session_handler_wrapper.session_handler {
-            SessionHandler::NonPty(npty) => {
-                npty.task_handle.abort();
-                session_handler_wrapper.session_handler = SessionHandler::Pty(PtyHandler::new(
-                    pty_data,
-                    session.handle(),
-                    session_handler_wrapper.data.clone(),
-                ))
+        match &mut session_handler_wrapper.session_handler {
+            SessionHandler::Pending(pty) => {
+                *pty = Some(pty_data);
             }
+            // too late for a report to become interactive
+            SessionHandler::NonPty(_) => {}
             SessionHandler::Pty(pty) => {
                 *pty.data.lock().await = pty_data;
             }
         }
         Ok(())
     }
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.start(channel, None, session)
+    }
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command=String::from_utf8_lossy(data).into_owned();
+        self.start(channel, Some(&command), session)
+    }
     async fn window_change_request(
         &mut self,
         channel: ChannelId,
@@ -174,17 +535,58 @@ impl server::Handler for SshClientHandler {
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         session.channel_success(channel)?;
-        let session_handler_wrapper = self
+        // refused channels have no handler
+        let Some(session_handler_wrapper) = self
             .sessions
             .iter_mut()
             .find(|x| x.data.chanel_id == channel)
-            .unwrap();
-        if let SessionHandler::Pty(pty) = &session_handler_wrapper.session_handler {
-            let mut lock = pty.data.lock().await;
-            lock.col_width = col_width;
-            lock.row_height = row_height;
-            lock.pix_width = pix_width;
-            lock.pix_height = pix_height;
+        else {
+            return Ok(());
+        };
+        let span=&mut session_handler_wrapper.span;
+        span.record("cols", col_width);
+        span.record("rows", row_height);
+        span.event(Level::Debug, "window resized", &[]);
+        if let Some(r) = &session_handler_wrapper.data.recorder {
+            r.resize(col_width, row_height);
+        }
+        let resize=|d: &mut PtyData| {
+            d.col_width = col_width;
+            d.row_height = row_height;
+            d.pix_width = pix_width;
+            d.pix_height = pix_height;
+        };
+        match &mut session_handler_wrapper.session_handler {
+            SessionHandler::Pending(Some(pty)) => resize(pty),
+            SessionHandler::Pty(pty) => resize(&mut *pty.data.lock().await),
+            _ => {}
+        }
+        Ok(())
+    }
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(i) = self.sessions.iter().position(|x| x.data.chanel_id == channel) {
+            // dropping the wrapper ends the session's log span
+            let wrapper = self.sessions.remove(i);
+            shutdown::unregister(wrapper.data.id);
+            // no one is left to read the rest of a report
+            if let SessionHandler::NonPty(job) = &wrapper.session_handler {
+                job.task_handle.abort();
+            }
+        }
+        Ok(())
+    }
+    async fn window_adjusted(
+        &mut self,
+        channel: ChannelId,
+        new_size: u32,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if let Some(wrapper) = self.sessions.iter().find(|x| x.data.chanel_id == channel) {
+            wrapper.data.window.update(new_size);
         }
         Ok(())
     }
@@ -194,24 +596,29 @@ impl server::Handler for SshClientHandler {
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let session_handler_wrapper = self
+        let Some(session_handler_wrapper) = self
             .sessions
             .iter()
             .find(|x| x.data.chanel_id == channel)
-            .unwrap();
+        else {
+            return Ok(());
+        };
         if data == [3] {
             let session_handle = session.handle();
             match &session_handler_wrapper.session_handler {
-                SessionHandler::NonPty(_) => {
-                    panic!();
-                }
+                // without a terminal, a 3 is just a byte of input, which nothing reads
+                SessionHandler::Pending(_) | SessionHandler::NonPty(_) => {}
                 SessionHandler::Pty(_) => {
                     let exit_window=*session_handler_wrapper.data.exit_window.read().await;
+                    let restore=restore_sequence(exit_window);
+                    if let Some(r) = &session_handler_wrapper.data.recorder {
+                        r.output(&restore);
+                    }
                     tokio::spawn(async move {
                         session_handle
                             .data(
                                 channel,
-                                CryptoVec::from(format!("\x1b[0m\x1b[?25h{}", match exit_window{true=>"\x1b[?1049l", false=>""})),
+                                CryptoVec::from(restore),
                             )
                             .await
                             .unwrap();
@@ -222,316 +629,244 @@ impl server::Handler for SshClientHandler {
             }
             return Ok(());
         }
+        if let SessionHandler::Pty(pty) = &session_handler_wrapper.session_handler {
+            // the app may be waiting for replies to its terminal queries; if it isn't, or is
+            // falling behind, the input is dropped
+            let _ = pty.input.try_send(data.to_vec());
+        }
         Ok(())
     }
 }
+/// Runs a one-shot job without a terminal, then reports its exit status and closes the channel.
 struct NonPtyHandler {
     task_handle: JoinHandle<()>,
 }
+
 impl NonPtyHandler {
-    pub fn new(session: Handle, channel: ChannelId) -> Self {
-        NonPtyHandler {
-            task_handle: tokio::spawn(async move {
-                session
-                    .data(
-                        channel,
-                        CryptoVec::from(format!("waiting for ssh client to request pty...\n\r")),
-                    )
-                    .await
-                    .unwrap();
-            }),
-        }
+    pub fn new(session: Handle, session_data: SessionData, job: impl Future<Output = u32> + Send + 'static) -> Self {
+        let channel = session_data.chanel_id;
+        let task_handle = tokio::spawn(async move {
+            let status = job.await;
+            let _ = session.exit_status_request(channel, status).await;
+            let _ = session.eof(channel).await;
+            let _ = session.close(channel).await;
+        });
+        shutdown::set_app(session_data.id, task_handle.abort_handle(), false);
+        NonPtyHandler { task_handle }
     }
 }
+/// Chunks of terminal input queued for an app before more are dropped.
+const INPUT_QUEUE: usize = 16;
+
 struct PtyHandler {
     data: Arc<Mutex<PtyData>>,
+    /// Terminal input for the running app, read only while it waits for query replies.
+    input: mpsc::Sender<Vec<u8>>,
     task_handle: JoinHandle<()>,
 }
 impl PtyHandler {
     pub fn new(data: PtyData, session: Handle, session_data: SessionData) -> Self {
         let data = Arc::new(Mutex::new(data));
-        PtyHandler {
+        let (input, mut input_rx) = mpsc::channel(INPUT_QUEUE);
+        let id = session_data.id;
+        let pty = PtyHandler {
             data: data.clone(),
+            input,
             task_handle: tokio::spawn(async move {
-                match session_data.user.as_str() {
-                    "virus" => {
-                        let _ = status_mmessages(session, session_data.chanel_id).await;
-                    }
-                    "weather" => {
-                        let _ = weather(session, session_data.chanel_id, data, &session_data.user, session_data.ip).await;
-                    }
-                    s => {
-                        let _ = help(session, session_data.chanel_id, &session_data.user).await;
-                    }
+                let app = metrics::app(session_data.app);
+                // counted until the app returns or is aborted
+                let _active = metrics::ActiveApp::new(app);
+                let mut sched = FrameScheduler::new(
+                    session.clone(),
+                    session_data.chanel_id,
+                    session_data.window.clone(),
+                    session_data.sent.clone(),
+                    app,
+                );
+                if let Some(r) = &session_data.recorder {
+                    sched.record_to(r.clone());
                 }
+                // every app but help switches to the alternate screen
+                if session_data.app != "help" {
+                    *session_data.exit_window.write().await = true;
+                }
+                let colors = match session_data.app {
+                    "gif" | "weather" => osc::query_colors(&mut sched, &mut input_rx).await,
+                    _ => read_term_data(),
+                };
+                // nothing reads input from here on, so stop taking it
+                drop(input_rx);
+                let _ = run_app(session_data.app, sched, data, colors, &session_data.args, &session_data.user).await;
             }),
+        };
+        shutdown::set_app(id, pty.task_handle.abort_handle(), true);
+        pty
+    }
+}
+/// Runs `app` in a terminal until it ends, the client goes away or the task is aborted.
+async fn run_app(
+    app: &str,
+    sched: FrameScheduler,
+    data: Arc<Mutex<PtyData>>,
+    colors: Arc<TerminalData>,
+    args: &args::Args,
+    user: &str,
+) -> Result<(), CryptoVec> {
+    match app {
+        "virus" => {
+            let rng = seed::rng_from(args.int("seed").unwrap_or_else(seed::random));
+            status_mmessages(sched, args.flag("errors"), rng).await
         }
+        "gif" => player::gif(data, colors, sched).await,
+        "weather" => weather(data, colors, sched, args).await,
+        _ => help(sched, user).await,
     }
 }
-async fn help(session: Handle, channel: ChannelId, username: &str) -> Result<(), CryptoVec> {
-    session
-        .data(channel, CryptoVec::from(format!("Hello!\n\rThis server uses the ssh username as a way to communicate what should be sent. You have connected with the username \"{username}\". If this is your actual name, don't worry, it won't be saved / logged / sent anywhere.\n\rpossible usernames include:\n\r\"virus\"\n\r\"weather\"\n\r")))
+/// Logs and counts a connection or channel the limits turned away.
+fn refused(r: Refusal, ip: IpAddr) {
+    let mut fields=identity(ip, "");
+    fields.push(("reason", r.reason().into()));
+    logging::event(Level::Warn, "refused", &fields);
+    metrics::refused(r).inc();
+}
+/// The app a username selects.
+fn app_name(user: &str) -> &'static str {
+    match user {
+        "virus" => "virus",
+        "gif" => "gif",
+        "weather" => "weather",
+        _ => "help",
+    }
+}
+/// What help says, with plain `\n` line ends.
+fn help_text(username: &str) -> String {
+    let privacy=if config::config().log_identity{
+        "This server's logs may include it."
+    }else{
+        "If this is your actual name, don't worry, it won't be saved / logged / sent anywhere."
+    };
+    format!("Hello!\nThis server uses the ssh username as a way to communicate what should be sent. You have connected with the username \"{username}\". {privacy}\npossible usernames include:\n\"virus\"\n\"weather\"\n\"gif\"\nWithout a terminal, or as a command (ssh host weather), weather and virus print a report instead; add --json for JSON.\n")
+}
+async fn help(mut sched: FrameScheduler, username: &str) -> Result<(), CryptoVec> {
+    sched
+        .write(help_text(username).replace('\n', "\n\r"))
         .await?;
     sleep(Duration::from_millis(1)).await;
-    session.close(channel).await.unwrap();
+    sched.close().await;
     Ok(())
 }
-async fn status_mmessages(session: Handle, channel: ChannelId) -> Result<(), CryptoVec> {
-    session
-        .data(
-            channel,
-            CryptoVec::from(format!("\x1b[?1049h\x1b[?25l\x1b[2J\x1b[0;0H")),
-        )
+/// With `errors`, some of the steps fail instead of finishing.
+async fn status_mmessages(mut sched: FrameScheduler, errors: bool, mut rng: SeedRng) -> Result<(), CryptoVec> {
+    sched
+        .write("\x1b[?1049h\x1b[?25l\x1b[2J\x1b[0;0H")
         .await?;
-    let mut rng = SmallRng::from_rng(&mut rng());
     loop {
         let duration = rng.random_range::<f64, _>(0.0..1.0).powi(10) * 5.0;
         let start = Instant::now();
-        let message = &format!("{}...  ", generate_message());
-        session
-        .data(channel, CryptoVec::from(format!("\x1b[0m{}", message)))
+        let message = &format!("{}...  ", generate_message(&mut rng));
+        sched
+        .write(format!("\x1b[0m{}", message))
         .await?;
     while start.elapsed().as_millis() < (duration * 1000.0) as u128 {
         sleep(Duration::from_millis(
                 (rng.random_range::<f32, _>(0.0..1.0).powi(5) * 200.0) as u64,
             ))
             .await;
-        session
-                .data(
-                    channel,
-                    CryptoVec::from(format!(
+        sched
+                .write(format!(
                         "\x1b[0m\x1b[{}G\x1b[0K{}%",
                         message.len(),
                         ((start.elapsed().as_millis() as f64) / (1000.0 * duration) * 100.0)
                         as u128
-                    )),
-                )
+                    ))
                 .await?;
         }
-        session
-        .data(
-                channel,
-                CryptoVec::from(format!("\x1b[32m\x1b[{}G\x1b[0Kdone!\n\r", message.len(),)),
-            )
+        let (colour, outcome)=if errors&&rng.random_bool(FAILURE_CHANCE){
+            (31, "failed!")
+        }else{
+            (32, "done!")
+        };
+        sched
+        .write(format!("\x1b[{colour}m\x1b[{}G\x1b[0K{outcome}\n\r", message.len(),))
             .await?;
     }
 }
-struct Particle{
-    x:f64,
-    y:f64,
-    z:f64,
-}
-struct Triangle{
-    p:[Vec3;3],
-}
-impl Triangle{
-    pub fn area(&self)->f64{
-        (self.p[0]-self.p[1]).cross(&(self.p[0]-self.p[2])).len()
-    }
-    pub fn random_point(&self, rng:&mut SmallRng)->Vec3{
-        let mut xn=rng.random();
-        let mut yn=rng.random();
-        if xn+yn>1.0{
-            xn=0.5-xn;
-            yn=0.5-yn;
-        }
-        self.p[0]+(self.p[1]-self.p[0])*xn+(self.p[2]-self.p[0])*yn
+async fn weather(data: Arc<Mutex<PtyData>>, fd: Arc<TerminalData>, mut sched: FrameScheduler, args: &args::Args) -> Result<(), CryptoVec>{
+    if let Some(fps)=args.float("fps"){
+        sched.set_fps(fps);
     }
-    pub fn normal(&self)->Vec3{
-        (self.p[0]-self.p[1]).cross(&(self.p[0]-self.p[2])).normalize()
-    }
-}
-async fn weather(session: Handle, channel: ChannelId, data: Arc<Mutex<PtyData>>, username: &str, ip:IpAddr) -> Result<(), CryptoVec>{
-    let fd=read_term_data();
-    let d0=data.lock().await;
-    let mut f=Frame::new(d0.col_width as usize, d0.row_height as usize, ());
-    session
-    .data(
-        channel,
-            CryptoVec::from(format!("\x1b[?1049h\x1b[?25l\x1b[2J\x1b[0;0H")),
-        )
+    let (w, h)={
+        let d0=data.lock().await;
+        (d0.col_width as usize, d0.row_height as usize)
+    };
+    let mut f=Frame::new(w, h, ());
+    // layers are rendered at sub-cell resolution when the glyph table allows shape matching
+    // and the server isn't short on CPU
+    let shapes=shape_grid(&fd).unwrap_or(1);
+    let mut grid=0;
+    let (mut lw, mut lh)=(0, 0);
+    let mut layers=LayerStack::new(0, 0);
+    let mut cam=Camera::new(1, 1);
+    sched
+        .write("\x1b[?1049h\x1b[?25l\x1b[2J\x1b[0;0H")
         .await?;
-    /*
-    puddles
-    snow/rain drops/flakes
-    calculate average movement from wind+gravity
-    calculate camera edge planes
-    for each camera edge plane:
-    calculate area
-    the more area, the more random points are checked and have a chance to generate a snowflake if the wind's strong enough.
-    area * normal dot movement = amount of snowflakes to spawn in this area
-    wind
-    ripples
-    grass?
-    sun
-    clouds
-    */
-    let mut particles:Vec<Particle>=vec![Particle{x:0.0, y:-1.0, z:1.0}];
-    let mut rng = SmallRng::from_rng(&mut rng());
-    let noise=noise::Simplex::new(0);
-    let mut t=0.0;
-    let windx=0.02;
-    let windz=-0.02;
-    let tmult=5.0;
+    // everyone watching the same scene sees the same snow, only the projection is per session
+    let mut scene=world::subscribe(&report::scene(args));
     loop {
-        t+=1.0*tmult;
-        let horizon_height=f.height as f64*0.3;
-        let y3d=5.0;
-        let cam_origin=Vec3::new(0.0,0.0,0.0);
-        let see_distance=10.0;
-        let camplanez=1.0;
-        let camdir_top_left=Vec3::new(-(f.width as f64 / f.height as f64), -1.0, camplanez);
-        let camdir_top_right=Vec3::new(-camdir_top_left.c[0], camdir_top_left.c[1], camdir_top_left.c[2]);
-        let camdir_bottom_left=Vec3::new(camdir_top_left.c[0], -camdir_top_left.c[1], camdir_top_left.c[2]);
-        let camdir_bottom_right=Vec3::new(-camdir_top_left.c[0], -camdir_top_left.c[1], camdir_top_left.c[2]);
-        let camdir_down=camdir_bottom_left-camdir_top_left;
-        let camdir_right=camdir_bottom_right-camdir_bottom_left;
-        let cam_top_left=camdir_top_left/camplanez*see_distance;
-        let cam_top_right=camdir_top_right/camplanez*see_distance;
-        let mut cam_bottom_left_far=camdir_bottom_left/camplanez*see_distance;
-        cam_bottom_left_far.c[1]=y3d;
-        let mut cam_bottom_right_far=camdir_bottom_right/camplanez*see_distance;
-        cam_bottom_right_far.c[1]=y3d;
-        let cam_bottom_left_near=Vec3::new(camdir_bottom_left.c[0]*y3d/camdir_bottom_left.c[2], y3d, camdir_bottom_left.c[1]*y3d/camdir_bottom_left.c[2]);
-        let cam_bottom_right_near=Vec3::new(camdir_bottom_right.c[0]*y3d/camdir_bottom_right.c[2], y3d, camdir_bottom_right.c[1]*y3d/camdir_bottom_right.c[2]);
-        let snow_spawners=&[
-            Triangle{// top
-                p:[
-                    cam_origin,
-                    cam_top_left,
-                    cam_top_right,
-                ]
-            },
-            Triangle{// bottom
-                p:[
-                    cam_origin,
-                    cam_bottom_right_near,
-                    cam_bottom_left_near,
-                ]
-            },
-            Triangle{// left near
-                p:[
-                    cam_origin,
-                    cam_bottom_left_near,
-                    cam_top_left,
-                ]
-            },
-            Triangle{// left far
-                p:[
-                    cam_bottom_left_near,
-                    cam_bottom_left_far,
-                    cam_top_left,
-                ]
-            },
-            Triangle{// right near
-                p:[
-                    cam_origin,
-                    cam_top_right,
-                    cam_bottom_right_near,
-                ]
-            },
-            Triangle{// right far
-                p:[
-                    cam_bottom_right_near,
-                    cam_top_right,
-                    cam_bottom_right_far,
-                ]
-            },
-            Triangle{// far top left
-                p:[
-                    cam_top_left,
-                    cam_bottom_left_far,
-                    cam_top_right,
-                ]
-            },
-            Triangle{// far bottom right
-                p:[
-                    cam_bottom_right_far,
-                    cam_top_right,
-                    cam_bottom_left_far,
-                ]
-            },
-        ];
-        for y in 0..f.height{
-            for x in 0..f.width{
+        sched.tick().await;
+        match scene.has_changed(){
+            Ok(true)=>{}
+            // nothing new to show since the last frame
+            Ok(false)=>continue,
+            Err(_)=>return Ok(()),
+        }
+        let started=std::time::Instant::now();
+        let g=if sched.quality()==Quality::Full{shapes}else{1};
+        if g!=grid{
+            grid=g;
+            (lw, lh)=(f.width*grid, f.height*grid);
+            layers=LayerStack::new(lw, lh);
+            cam=Camera::new(lw, lh);
+        }
+        let horizon_height=lh as f64*0.3;
+        let state=scene.borrow_and_update().clone();
+        layers.clear();
+        let ground=layers.layer_mut(LayerKind::Ground);
+        for y in 0..lh{
+            for x in 0..lw{
                 if y as f64 > horizon_height{
-                    let lookdir=camdir_top_left+camdir_right*(x as f64 / f.width as f64)+camdir_down*(y as f64 / f.height as f64);
-
-                    let z3d=lookdir.c[2]/lookdir.c[1] * y3d;
-                    let x3d=lookdir.c[0]/lookdir.c[1] * y3d;
-                    let quantum_y3d=noise.get([x3d/30.0, z3d/30.0]);
+                    let lookdir=cam.ray(x as f64, y as f64);
+                    // rays at or above eye level never reach the ground
+                    if lookdir.c[1]<=0.0{
+                        continue;
+                    }
+                    let z3d=lookdir.c[2]/lookdir.c[1] * GROUND_Y;
+                    let x3d=lookdir.c[0]/lookdir.c[1] * GROUND_Y;
+                    let quantum_y3d=state.noise.get([x3d/30.0, z3d/30.0]);
                     if quantum_y3d>0.4&&z3d>0.0{
-                        f.set_texel(x, y, ('q', ())).unwrap();
-                    }else{
-                        f.set_texel(x, y, (' ', ())).unwrap();
+                        ground.set_pixel_depth(x, y, z3d as f32, [190, 190, 190], 1.0).unwrap();
                     }
-                }else{
-                    f.set_texel(x, y, (' ', ())).unwrap();
                 }
             }
         }
-        let snow_amount=0.0001*f.height as f64;
-        for tr in snow_spawners{
-            let tries=snow_amount*tr.area()*tmult;
-            let prob_plus_1=tries%1.0;
-            let tries=if rng.random::<f64>()<prob_plus_1{
-                tries.ceil() as usize
-            }else{
-                tries.floor() as usize
-            };
-            let normal=tr.normal();
-            for _ in 0..tries{
-                let point=tr.random_point(&mut rng);
-                let wind=get_wind(noise, t, windx, windz, &point);
-                let prob=wind.dot(&normal);
-                if rng.random::<f64>()< prob{
-                    particles.push(Particle { x: point.c[0], y: point.c[1], z: point.c[2] });
-                }
+        let particle_layer=layers.layer_mut(LayerKind::Particles);
+        for p in &state.particles{
+            if let Some((sx, sy, _))=cam.project(*p)
+                && sx>0.0&&sx<lw as f64&&sy>0.0&&sy<lh as f64{
+                let (sx, sy)=(sx as usize, sy as usize);
+                let z=p.c[2];
+                let v=(2000.0/z/z) as u8;
+                let _=particle_layer.set_pixel_depth(sx, sy, z as f32, [v, v, v], 1.0);
             }
         }
-        {
-            let f=&mut f;
-            let td=&fd;
-            particles=particles.drain(..).filter_map(move |mut p| {
-                p.y+=0.03*tmult;
-                p.x+=windx*tmult;
-                p.z+=windz*tmult;
-                let pvec = Vec3::new(p.x,p.y,p.z);
-                let curl = get_wind(noise, t, windx, windz, &pvec);
-                p.x+=curl.c[0]/100.0*tmult;
-                p.y+=curl.c[1]/100.0*tmult;
-                p.z+=curl.c[2]/100.0*tmult;
-                if p.y<y3d&&p.z>0.0{
-                    let lcomb=(camdir_top_left- pvec).lin_comb(-camdir_right, -camdir_down, cam_origin-pvec);
-                    if lcomb.c[0]>0.0&&lcomb.c[0]<1.0&&lcomb.c[1]>0.0&&lcomb.c[1]<1.0{
-                        let sx=(lcomb.c[0]*f.width as f64) as usize;
-                        let sy=(lcomb.c[1]*f.height as f64) as usize;
-                        let _=f.set_pixel(sx, sy, (2000.0/p.z/p.z) as u8, (2000.0/p.z/p.z) as u8, (2000.0/p.z/p.z) as u8, td);
-                    }
-                    Some(p)
-                }else{
-                    None
-                }
-            }).collect();
+        if grid>1{
+            layers.composite_into_shapes(&mut f, &*fd, grid);
+        }else{
+            layers.composite_into(&mut f, &*fd, Dither::BlueNoise);
         }
-        session
-            .data(
-                channel,
-                CryptoVec::from(f.render_str()),
-            )
-            .await?;
-        sleep(Duration::from_millis(30)).await;
+        let frame=f.render_str();
+        sched.rendered(started.elapsed());
+        metrics::metrics().particles.set(state.particles.len() as i64);
+        sched.send(frame).await?;
     }
 }
-
-fn get_wind(noise: Simplex, t: f64, windx: f64, windz: f64, p: &Vec3) -> Vec3 {
-    curl_noise_3d_t(&noise, (p.c[0]-windx*t)/10.0, p.c[1]/10.0, (p.c[2]-windz*t)/10.0, t*0.003)
-}
-pub fn curl_noise_3d_t(noise:&Simplex, x:f64, y:f64, z:f64, t:f64)->Vec3{
-    const D:f64=0.001;
-    Vec3::new(
-        (noise.get([x, y+D, z, t+2000.0])-noise.get([x, y-D, z, t+2000.0]))/2.0/D-(noise.get([x, y, z+D, t+1000.0])-noise.get([x, y, z-D, t+1000.0]))/2.0/D,
-        (noise.get([x, y, z+D, t])-noise.get([x, y, z-D, t]))/2.0/D-(noise.get([x+D, y, z, t+2000.0])-noise.get([x-D, y, z, t+2000.0]))/2.0/D,
-        (noise.get([x+D, y, z, t+1000.0])-noise.get([x-D, y, z, t+1000.0]))/2.0/D-(noise.get([x, y+D, z, t])-noise.get([x, y-D, z, t]))/2.0/D,
-    )
-}
\ No newline at end of file
@@ -1,4 +1,7 @@
-use rand::{Rng, rngs::ThreadRng};
+use rand::Rng;
+
+/// Share of steps that fail when errors are asked for.
+pub const FAILURE_CHANCE: f64 = 0.2;
 
 const VERBS: &[&str] = &[
     "Enter",
@@ -369,34 +372,32 @@ const ERRORTEMPLATES: &[&str] = &[
     "no {snoun} found",
     "{gnoun} {unverb}ed",
 ];
-fn random(rng: &mut ThreadRng, list: &[&str]) -> String {
+fn random(rng: &mut impl Rng, list: &[&str]) -> String {
     list[rng.random_range(0..list.len())].to_string()
 }
-pub fn generate_message() -> String {
+pub fn generate_message(rng: &mut impl Rng) -> String {
     let positive = false;
-    let mut rng = rand::rng();
     let unverb = rng.random_range(0.0..1.0) < 0.5;
     let verb = random(
-        &mut rng,
+        rng,
         match unverb {
             true => UNVERBS,
             false => VERBS,
         },
     );
-    let noun = generate_noun(&mut rng, unverb == positive);
+    let noun = generate_noun(rng, unverb == positive);
     format!("{verb}ing {noun}")
 }
-pub fn generate_error() -> String {
-    let mut rng = rand::rng();
-    let mut template = random(&mut rng, ERRORTEMPLATES);
+pub fn generate_error(rng: &mut impl Rng) -> String {
+    let mut template = random(rng, ERRORTEMPLATES);
     while template.contains("{snoun}") {
-        template = template.replacen("{snoun}", &generate_snoun(&mut rng, false).0, 1);
+        template = template.replacen("{snoun}", &generate_snoun(rng, false).0, 1);
     }
     while template.contains("{gnoun}") {
-        template = template.replacen("{gnoun}", &generate_noun(&mut rng, false), 1);
+        template = template.replacen("{gnoun}", &generate_noun(rng, false), 1);
     }
     while template.contains("{unverb}") {
-        template = template.replacen("{unverb}", &random(&mut rng, UNVERBS), 1);
+        template = template.replacen("{unverb}", &random(rng, UNVERBS), 1);
     }
     template
 }
@@ -408,7 +409,7 @@ noun: noun | adjective noun
 negative: uverb noun | verb unoun
 positive: verb noun | uverb unoun
 */
-pub fn generate_noun(rng: &mut ThreadRng, u: bool) -> String {
+pub fn generate_noun(rng: &mut impl Rng, u: bool) -> String {
     let mut the = false;
     let noun = if u {
         let adj: f32 = rng.random();
@@ -443,7 +444,7 @@ pub fn generate_noun(rng: &mut ThreadRng, u: bool) -> String {
     if the { format!("the {noun}") } else { noun }
 }
 
-fn generate_snoun(rng: &mut ThreadRng, u: bool) -> (String, bool) {
+fn generate_snoun(rng: &mut impl Rng, u: bool) -> (String, bool) {
     let kind: f32 = rng.random();
     let nouns = &[NOUNS, FNOUNS].concat()[..];
     let unnouns = &[UNNOUNS, FUNNOUNS].concat()[..];
@@ -0,0 +1,230 @@
+//! Counters for a Prometheus scraper, served as plain text over HTTP on `WEATHER_SSH_METRICS`
+//! when that is set. Everything is a static atomic, so recording costs next to nothing when
+//! nobody is scraping.
+
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        OnceLock,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::limits::Refusal;
+use crate::logging::{self, Level};
+
+/// Every app a session can run, see `app_name`.
+pub const APPS: [&str; 4] = ["help", "virus", "weather", "gif"];
+/// Upper bounds of the render time buckets, in seconds.
+const RENDER_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+/// Longest request head read before answering.
+const MAX_REQUEST: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Render times, bucketed by `RENDER_BUCKETS`.
+#[derive(Default)]
+pub struct Histogram {
+    /// Not cumulative; summed up when exported.
+    buckets: [AtomicU64; RENDER_BUCKETS.len()],
+    sum_ns: AtomicU64,
+    count: AtomicU64,
+}
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        if let Some(i) = RENDER_BUCKETS.iter().position(|&b| secs <= b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_ns.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What is counted separately for each app.
+#[derive(Default)]
+pub struct AppMetrics {
+    pub active: Gauge,
+    pub sessions: Counter,
+    pub frames: Counter,
+    /// Frames skipped because the client or the app's clock was behind.
+    pub dropped: Counter,
+    pub bytes: Counter,
+    pub render: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub connections: Counter,
+    pub auth_accepted: Counter,
+    pub auth_rejected: Counter,
+    /// Connections turned away, by `Refusal`.
+    refused: [Counter; Refusal::ALL.len()],
+    /// Snowflakes in the weather scene at its last rendered frame.
+    pub particles: Gauge,
+    apps: [AppMetrics; APPS.len()],
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Default::default)
+}
+pub fn refused(r: Refusal) -> &'static Counter {
+    &metrics().refused[r as usize]
+}
+/// The metrics of the app named `name`; unknown names count as help, like they do as usernames.
+pub fn app(name: &str) -> &'static AppMetrics {
+    let i = APPS.iter().position(|&a| a == name).unwrap_or(0);
+    &metrics().apps[i]
+}
+
+/// Counts a running app as active for as long as this is alive.
+pub struct ActiveApp(&'static AppMetrics);
+impl ActiveApp {
+    pub fn new(app: &'static AppMetrics) -> Self {
+        app.active.add(1);
+        app.sessions.inc();
+        ActiveApp(app)
+    }
+}
+impl Drop for ActiveApp {
+    fn drop(&mut self) {
+        self.0.active.add(-1);
+    }
+}
+
+/// The metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let m = metrics();
+    let mut out = String::new();
+    family(&mut out, "connections_total", "counter", "SSH connections accepted.");
+    let _ = writeln!(out, "weather_ssh_connections_total {}", m.connections.get());
+    family(&mut out, "auth_total", "counter", "Authentication attempts by outcome.");
+    let _ = writeln!(out, "weather_ssh_auth_total{{outcome=\"accepted\"}} {}", m.auth_accepted.get());
+    let _ = writeln!(out, "weather_ssh_auth_total{{outcome=\"rejected\"}} {}", m.auth_rejected.get());
+    family(&mut out, "refused_total", "counter", "Connections turned away by the limits, by reason.");
+    for r in Refusal::ALL {
+        let _ = writeln!(out, "weather_ssh_refused_total{{reason=\"{}\"}} {}", r.reason(), refused(r).get());
+    }
+    family(&mut out, "weather_particles", "gauge", "Snowflakes in the weather scene.");
+    let _ = writeln!(out, "weather_ssh_weather_particles {}", m.particles.get());
+    // name, type, help and how to read the value
+    let per_app: [(&str, &str, &str, Sample); 5] = [
+        ("active_sessions", "gauge", "Sessions currently running an app.", |a| a.active.get().to_string()),
+        ("sessions_total", "counter", "Apps started.", |a| a.sessions.get().to_string()),
+        ("frames_total", "counter", "Frames sent.", |a| a.frames.get().to_string()),
+        ("frames_dropped_total", "counter", "Frames skipped because the client or the app was behind.", |a| {
+            a.dropped.get().to_string()
+        }),
+        ("bytes_sent_total", "counter", "Bytes sent to clients.", |a| a.bytes.get().to_string()),
+    ];
+    for (name, kind, help, value) in per_app {
+        family(&mut out, name, kind, help);
+        for (app, a) in APPS.iter().zip(&m.apps) {
+            let _ = writeln!(out, "weather_ssh_{name}{{app=\"{app}\"}} {}", value(a));
+        }
+    }
+    family(&mut out, "render_seconds", "histogram", "Time spent rendering one frame.");
+    for (app, a) in APPS.iter().zip(&m.apps) {
+        let h = &a.render;
+        let mut cumulative = 0;
+        for (bound, bucket) in RENDER_BUCKETS.iter().zip(&h.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "weather_ssh_render_seconds_bucket{{app=\"{app}\",le=\"{bound}\"}} {cumulative}");
+        }
+        let count = h.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "weather_ssh_render_seconds_bucket{{app=\"{app}\",le=\"+Inf\"}} {count}");
+        let sum = h.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "weather_ssh_render_seconds_sum{{app=\"{app}\"}} {sum}");
+        let _ = writeln!(out, "weather_ssh_render_seconds_count{{app=\"{app}\"}} {count}");
+    }
+    out
+}
+type Sample = fn(&AppMetrics) -> String;
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = write!(out, "# HELP weather_ssh_{name} {help}\n# TYPE weather_ssh_{name} {kind}\n");
+}
+
+/// Serves `GET /metrics` on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    logging::event(Level::Info, "serving metrics", &[("addr", addr.to_string().into())]);
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                logging::event(Level::Warn, "metrics accept failed", &[("error", e.to_string().into())]);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let _ = timeout(REQUEST_TIMEOUT, respond(stream)).await;
+        });
+    }
+}
+
+/// Answers one request and closes the connection.
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let line = head.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render()),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+    let content_type = if status.starts_with("200") {
+        "text/plain; version=0.0.4; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,248 @@
+//! Asks the client's terminal for its actual colours (OSC 4 for the 16 ANSI colours, OSC 10/11
+//! for the default foreground/background) so texels are matched against what the user sees
+//! instead of the palette in colors.txt.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{Instant, timeout_at},
+};
+
+use crate::scheduler::FrameScheduler;
+use crate::termdata::{TerminalData, read_term_data};
+
+/// How long to wait for replies; terminals that ignore OSC queries never send any.
+const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+/// Longest sequence kept while waiting for its terminator, anything longer is garbage.
+const MAX_SEQUENCE: usize = 256;
+
+/// The queries, followed by a primary device attributes request: every terminal answers that
+/// one, and in order, so its reply means there are no colour replies left to wait for.
+fn query() -> String {
+    let mut q = String::new();
+    for i in 0..16 {
+        q += &format!("\x1b]4;{i};?\x1b\\");
+    }
+    q += "\x1b]10;?\x1b\\\x1b]11;?\x1b\\\x1b[c";
+    q
+}
+
+/// Colours collected from the replies so far.
+#[derive(Default)]
+pub struct ColorReplies {
+    pub palette: [Option<[u8; 3]>; 16],
+    pub foreground: Option<[u8; 3]>,
+    pub background: Option<[u8; 3]>,
+    /// The device attributes reply arrived.
+    pub done: bool,
+    buf: Vec<u8>,
+}
+
+/// Parses an X11 colour spec like `rgb:ffff/8080/0000`, each part being 1 to 4 hex digits.
+fn parse_color(spec: &str) -> Option<[u8; 3]> {
+    let mut parts = spec.strip_prefix("rgb:")?.split('/');
+    let mut rgb = [0; 3];
+    for c in &mut rgb {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 4 {
+            return None;
+        }
+        let v = u32::from_str_radix(part, 16).ok()?;
+        let max = (1u32 << (4 * part.len())) - 1;
+        *c = ((v * 255 + max / 2) / max) as u8;
+    }
+    parts.next().is_none().then_some(rgb)
+}
+
+impl ColorReplies {
+    /// Feeds terminal input; may be split anywhere, even inside a reply.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        loop {
+            let Some(start) = self.buf.iter().position(|&b| b == 0x1b) else {
+                self.buf.clear();
+                return;
+            };
+            self.buf.drain(..start);
+            let Some(&kind) = self.buf.get(1) else {
+                return;
+            };
+            let consumed = match kind {
+                b']' => {
+                    let end = (2..self.buf.len()).find_map(|i| match self.buf[i] {
+                        0x07 => Some((i, 1)),
+                        0x1b if self.buf.get(i + 1) == Some(&b'\\') => Some((i, 2)),
+                        _ => None,
+                    });
+                    match end {
+                        Some((end, term)) => {
+                            let body = String::from_utf8_lossy(&self.buf[2..end]).into_owned();
+                            self.osc(&body);
+                            end + term
+                        }
+                        None if self.buf.len() > MAX_SEQUENCE => 1,
+                        None => return,
+                    }
+                }
+                b'[' => match (2..self.buf.len()).find(|&i| (0x40..=0x7e).contains(&self.buf[i])) {
+                    Some(end) => {
+                        if self.buf[end] == b'c' && self.buf[2] == b'?' {
+                            self.done = true;
+                        }
+                        end + 1
+                    }
+                    None if self.buf.len() > MAX_SEQUENCE => 1,
+                    None => return,
+                },
+                _ => 1,
+            };
+            self.buf.drain(..consumed);
+        }
+    }
+    fn osc(&mut self, body: &str) {
+        let mut fields = body.split(';');
+        match fields.next() {
+            Some("4") => {
+                // a reply may carry several index;colour pairs
+                while let (Some(i), Some(spec)) = (fields.next(), fields.next()) {
+                    if let (Ok(i), Some(rgb)) = (i.parse::<usize>(), parse_color(spec))
+                        && i < 16
+                    {
+                        self.palette[i] = Some(rgb);
+                    }
+                }
+            }
+            Some("10") => self.foreground = fields.next().and_then(parse_color).or(self.foreground),
+            Some("11") => self.background = fields.next().and_then(parse_color).or(self.background),
+            _ => {}
+        }
+    }
+    /// `base` with the reported colours filled in, `None` if the terminal reported nothing.
+    pub fn apply(&self, base: &TerminalData) -> Option<TerminalData> {
+        if self.palette.iter().all(Option::is_none) && self.foreground.is_none() && self.background.is_none() {
+            return None;
+        }
+        let mut data = base.clone();
+        for (i, rgb) in self.palette.iter().enumerate() {
+            if let Some(rgb) = rgb {
+                data.palette[i] = *rgb;
+            }
+        }
+        data.foreground = self.foreground.or(base.foreground);
+        data.background = self.background.or(base.background);
+        data.share_luts();
+        Some(data)
+    }
+}
+
+/// Queries the terminal `sched` writes to and returns the tables for this session. Terminal
+/// input arrives on `input`; anything that isn't a reply is dropped while waiting.
+pub async fn query_colors(sched: &mut FrameScheduler, input: &mut Receiver<Vec<u8>>) -> Arc<TerminalData> {
+    let base = read_term_data();
+    if sched.write(query()).await.is_err() {
+        return base;
+    }
+    let mut replies = ColorReplies::default();
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    while !replies.done {
+        match timeout_at(deadline, input.recv()).await {
+            Ok(Some(bytes)) => replies.feed(&bytes),
+            _ => break,
+        }
+    }
+    replies.apply(&base).map(Arc::new).unwrap_or(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fed(chunks: &[&[u8]]) -> ColorReplies {
+        let mut r = ColorReplies::default();
+        for c in chunks {
+            r.feed(c);
+        }
+        r
+    }
+
+    #[test]
+    fn color_specs() {
+        assert_eq!(parse_color("rgb:f/8/0"), Some([255, 136, 0]));
+        assert_eq!(parse_color("rgb:ff/80/00"), Some([255, 128, 0]));
+        assert_eq!(parse_color("rgb:fff/800/000"), Some([255, 128, 0]));
+        assert_eq!(parse_color("rgb:ffff/8080/0000"), Some([255, 128, 0]));
+        // the parts needn't agree on their length
+        assert_eq!(parse_color("rgb:0/ff/ffff"), Some([0, 255, 255]));
+        for bad in ["rgb:fffff/0/0", "rgb:/0/0", "rgb:1/2", "rgb:1/2/3/4", "rgb:g/0/0", "#ff0000", "rgba:f/f/f/f", ""] {
+            assert_eq!(parse_color(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn terminators() {
+        let r = fed(&[b"\x1b]10;rgb:ffff/0000/0000\x07\x1b]11;rgb:0000/0000/ffff\x1b\\"]);
+        assert_eq!(r.foreground, Some([255, 0, 0]));
+        assert_eq!(r.background, Some([0, 0, 255]));
+    }
+
+    #[test]
+    fn split_replies() {
+        let reply = b"\x1b]4;3;rgb:aaaa/5555/0000\x1b\\\x1b]11;rgb:1010/1010/1010\x07";
+        // every byte on its own, so splits land inside the ST and between ESC and ]
+        let bytes: Vec<&[u8]> = reply.chunks(1).collect();
+        let r = fed(&bytes);
+        assert_eq!(r.palette[3], Some([170, 85, 0]));
+        assert_eq!(r.background, Some([16, 16, 16]));
+        assert!(r.buf.is_empty());
+    }
+
+    #[test]
+    fn several_pairs() {
+        let r = fed(&[b"\x1b]4;1;rgb:ff/00/00;2;rgb:00/ff/00;16;rgb:00/00/ff\x1b\\"]);
+        assert_eq!(r.palette[1], Some([255, 0, 0]));
+        assert_eq!(r.palette[2], Some([0, 255, 0]));
+        // only the 16 ANSI colours are kept
+        assert_eq!(r.palette.iter().flatten().count(), 2);
+    }
+
+    #[test]
+    fn garbage() {
+        let r = fed(&[
+            b"typed ahead\x1b[1;2H\x1bZ\x1b]999;rgb:ff/ff/ff\x07\x1b]10;not a colour\x07",
+            b"\x1b]4;x;rgb:ff/ff/ff\x07\x1b]10;rgb:12/34/56\x07",
+        ]);
+        assert_eq!(r.foreground, Some([0x12, 0x34, 0x56]));
+        assert!(r.palette.iter().all(Option::is_none) && r.background.is_none() && !r.done);
+        // a bad reply doesn't undo a good one
+        let mut r = r;
+        r.feed(b"\x1b]10;rgb:zz/zz/zz\x07");
+        assert_eq!(r.foreground, Some([0x12, 0x34, 0x56]));
+    }
+
+    #[test]
+    fn unterminated_sequence_is_dropped() {
+        let mut r = ColorReplies::default();
+        r.feed(b"\x1b]11;");
+        for _ in 0..100 {
+            r.feed(b"aaaaaaaaaa");
+            assert!(r.buf.len() <= MAX_SEQUENCE + 10);
+        }
+        // and whatever comes after it still counts
+        r.feed(b"\x1b]11;rgb:ff/ff/ff\x07");
+        assert_eq!(r.background, Some([255, 255, 255]));
+        assert!(r.buf.is_empty());
+    }
+
+    #[test]
+    fn device_attributes_end_the_query() {
+        // only the DA1 reply, not other CSI input
+        assert!(!fed(&[b"\x1b[2c\x1b[?1;2R\x1b[A"]).done);
+        let r = fed(&[b"\x1b]10;rgb:ff/ff/ff\x07\x1b[?6", b"2;22c"]);
+        assert!(r.done);
+        assert_eq!(r.foreground, Some([255, 255, 255]));
+        // a terminal without OSC support answers only this, which is enough to stop waiting
+        let r = fed(&[b"\x1b[?1;0c"]);
+        assert!(r.done && r.apply(&read_term_data()).is_none());
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fmt, io, path::Path};
+
+use image::{DynamicImage, ImageReader, RgbaImage, imageops::FilterType};
+
+use crate::dither::Dither;
+use crate::frame::Size;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Decode(image::ImageError),
+    /// The requested size works out to zero texels in some direction.
+    Empty,
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "could not read image: {e}"),
+            ImageError::Decode(e) => write!(f, "could not decode image: {e}"),
+            ImageError::Empty => write!(f, "image has no area at the requested size"),
+        }
+    }
+}
+impl std::error::Error for ImageError {}
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        ImageError::Io(e)
+    }
+}
+impl From<image::ImageError> for ImageError {
+    fn from(e: image::ImageError) -> Self {
+        ImageError::Decode(e)
+    }
+}
+
+pub fn load_image(path: impl AsRef<Path>) -> Result<DynamicImage, ImageError> {
+    Ok(ImageReader::open(path)?.with_guessed_format()?.decode()?)
+}
+pub fn load_image_bytes(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    Ok(image::load_from_memory(bytes)?)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resample {
+    /// Each texel is the coverage-weighted average of the pixels under it. Fast, no ringing.
+    Area,
+    Lanczos3,
+}
+
+pub struct ImageOptions {
+    pub size: Size<usize>,
+    /// Height of a terminal cell divided by its width.
+    pub cell_aspect: f32,
+    pub resample: Resample,
+    pub dither: Dither,
+    /// Match glyph shapes against sub-cell detail (see `shape`) instead of dithering.
+    pub shapes: bool,
+}
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            size: Size::Fit(80, 24),
+            cell_aspect: 2.0,
+            resample: Resample::Area,
+            dither: Dither::None,
+            shapes: false,
+        }
+    }
+}
+
+/// Scales `image` to `width`x`height` RGBA pixels.
+pub fn resample(image: &DynamicImage, width: usize, height: usize, mode: Resample) -> RgbaImage {
+    let src = image.to_rgba8();
+    match mode {
+        Resample::Lanczos3 => {
+            image::imageops::resize(&src, width as u32, height as u32, FilterType::Lanczos3)
+        }
+        Resample::Area => {
+            let xw = area_weights(src.width() as usize, width);
+            let yw = area_weights(src.height() as usize, height);
+            let mut out = RgbaImage::new(width as u32, height as u32);
+            for (ty, ys) in yw.iter().enumerate() {
+                for (tx, xs) in xw.iter().enumerate() {
+                    let mut acc = [0.0f32; 4];
+                    let mut total = 0.0;
+                    for &(sy, wy) in ys {
+                        for &(sx, wx) in xs {
+                            let p = src.get_pixel(sx as u32, sy as u32).0;
+                            let w = wx * wy;
+                            // weight colour by alpha so transparent pixels don't darken edges
+                            let a = p[3] as f32 * w;
+                            for c in 0..3 {
+                                acc[c] += p[c] as f32 * a;
+                            }
+                            acc[3] += a;
+                            total += w;
+                        }
+                    }
+                    let px = if acc[3] > 0.0 {
+                        [
+                            (acc[0] / acc[3]).round() as u8,
+                            (acc[1] / acc[3]).round() as u8,
+                            (acc[2] / acc[3]).round() as u8,
+                            (acc[3] / total).round() as u8,
+                        ]
+                    } else {
+                        [0; 4]
+                    };
+                    out.put_pixel(tx as u32, ty as u32, image::Rgba(px));
+                }
+            }
+            out
+        }
+    }
+}
+/// For every target pixel along one axis, the source pixels it overlaps and by how much.
+fn area_weights(src: usize, dst: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = src as f32 / dst as f32;
+    (0..dst)
+        .map(|t| {
+            let start = t as f32 * scale;
+            let end = (start + scale).max(start + 1.0).min(src as f32);
+            let start = start.min(end - 1.0).max(0.0);
+            let mut ws = Vec::new();
+            let mut s = start.floor() as usize;
+            while (s as f32) < end && s < src {
+                let w = (end.min(s as f32 + 1.0) - start.max(s as f32)).max(0.0);
+                if w > 0.0 {
+                    ws.push((s, w));
+                }
+                s += 1;
+            }
+            ws
+        })
+        .collect()
+}
@@ -0,0 +1,228 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use image::{
+    AnimationDecoder, DynamicImage, RgbaImage,
+    codecs::{gif::GifDecoder, png::PngDecoder},
+};
+use russh::CryptoVec;
+use tokio::{
+    sync::Mutex,
+    time::{Instant, sleep, sleep_until},
+};
+
+use crate::PtyData;
+use crate::config::config;
+use crate::dither::Dither;
+use crate::frame::{Frame, Size};
+use crate::picture::{ImageError, ImageOptions, load_image};
+use crate::scheduler::FrameScheduler;
+use crate::termdata::TerminalData;
+
+/// Delay used for image sequences and for frames that ask for (almost) none, like browsers do.
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+/// Animations that loop forever, and stills, are shown at least this long before moving on.
+const MIN_SHOW: Duration = Duration::from_secs(10);
+
+struct Animation {
+    frames: Vec<(RgbaImage, Duration)>,
+    /// How many times the file says to play it, `None` for forever.
+    plays: Option<u32>,
+}
+
+/// Every playable entry in the media folder: gif/png/apng files and directories of frames.
+fn media_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|d| d.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    entries.retain(|p| p.is_dir() || is_image(p));
+    entries.sort();
+    entries
+}
+fn is_image(p: &Path) -> bool {
+    matches!(
+        p.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("gif" | "png" | "apng" | "jpg" | "jpeg" | "webp" | "bmp")
+    )
+}
+fn frame_delay(d: image::Delay) -> Duration {
+    let (n, d) = d.numer_denom_ms();
+    let ms = n.checked_div(d).unwrap_or(0);
+    if ms < 20 {
+        DEFAULT_DELAY
+    } else {
+        Duration::from_millis(ms as u64)
+    }
+}
+/// How many times the GIF at `path` plays: once, plus the repeats of its NETSCAPE2.0 block.
+fn gif_plays(path: &Path) -> Result<Option<u32>, ImageError> {
+    let decoder = gif::DecodeOptions::new()
+        .read_info(BufReader::new(File::open(path)?))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(match decoder.repeat() {
+        gif::Repeat::Infinite => None,
+        gif::Repeat::Finite(n) => Some(n as u32 + 1),
+    })
+}
+/// How many times the APNG at `path` plays, 0 in its acTL chunk meaning forever.
+fn apng_plays(path: &Path) -> Result<Option<u32>, ImageError> {
+    let reader = png::Decoder::new(BufReader::new(File::open(path)?))
+        .read_info()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(reader.info().animation_control.map(|a| a.num_plays).filter(|&n| n > 0))
+}
+fn load_animation(path: &Path) -> Result<Animation, ImageError> {
+    // image sequences and stills have no loop count of their own
+    let mut plays = None;
+    let frames = if path.is_dir() {
+        let mut files: Vec<PathBuf> = fs::read_dir(path)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| is_image(p))
+            .collect();
+        files.sort();
+        files
+            .iter()
+            .map(|f| Ok((load_image(f)?.to_rgba8(), DEFAULT_DELAY)))
+            .collect::<Result<Vec<_>, ImageError>>()?
+    } else {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let frames = if ext.eq_ignore_ascii_case("gif") {
+            plays = gif_plays(path)?;
+            Some(GifDecoder::new(BufReader::new(File::open(path)?))?.into_frames())
+        } else if ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("apng") {
+            let png = PngDecoder::new(BufReader::new(File::open(path)?))?;
+            if png.is_apng()? {
+                plays = apng_plays(path)?;
+                Some(png.apng()?.into_frames())
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        match frames {
+            Some(frames) => frames
+                .collect_frames()?
+                .into_iter()
+                .map(|f| {
+                    let delay = frame_delay(f.delay());
+                    (f.into_buffer(), delay)
+                })
+                .collect(),
+            // a still image is just a one frame animation
+            None => vec![(load_image(path)?.to_rgba8(), DEFAULT_DELAY)],
+        }
+    };
+    if frames.is_empty() {
+        return Err(ImageError::Empty);
+    }
+    Ok(Animation { frames, plays })
+}
+
+/// Plays everything in the media folder in turn, scaled to the current window size.
+pub(crate) async fn gif(
+    data: Arc<Mutex<PtyData>>,
+    st: Arc<TerminalData>,
+    mut sched: FrameScheduler,
+) -> Result<(), CryptoVec> {
+    let entries = tokio::task::spawn_blocking(|| media_entries(&config().media_dir)).await.unwrap_or_default();
+    if entries.is_empty() {
+        sched.write("no animations to play, check back later!\n\r").await?;
+        sleep(Duration::from_millis(1)).await;
+        sched.close().await;
+        return Ok(());
+    }
+    sched.write("\x1b[?1049h\x1b[?25l\x1b[2J\x1b[0;0H").await?;
+    let opts_for = |w: usize, h: usize| ImageOptions {
+        size: Size::Fit(w, h),
+        dither: Dither::Bayer4,
+        ..Default::default()
+    };
+    loop {
+        let mut played = false;
+        for entry in &entries {
+            let path = entry.clone();
+            let animation = match tokio::task::spawn_blocking(move || load_animation(&path)).await {
+                Ok(Ok(a)) => a,
+                _ => continue,
+            };
+            played = true;
+            let started = Instant::now();
+            let mut plays = 0;
+            while animation.plays.map_or(started.elapsed() < MIN_SHOW, |n| plays < n) {
+                plays += 1;
+                let mut deadline = Instant::now();
+                for (buffer, delay) in &animation.frames {
+                    deadline += *delay;
+                    // behind after a slow send or render: drop frames to catch up with the clock
+                    if Instant::now() > deadline {
+                        sched.skipped();
+                        continue;
+                    }
+                    let started = std::time::Instant::now();
+                    let (w, h) = {
+                        let d = data.lock().await;
+                        (d.col_width as usize, d.row_height as usize)
+                    };
+                    let mut f = Frame::new(w, h, 0u16);
+                    let opts = opts_for(w, h);
+                    let (iw, ih) = opts.size.to_dimensions((
+                        ((opts.cell_aspect * buffer.width() as f32) as usize).max(1),
+                        (buffer.height() as usize).max(1),
+                    ));
+                    let image = DynamicImage::ImageRgba8(buffer.clone());
+                    let _ = f.put_image(
+                        (w.saturating_sub(iw) / 2) as isize,
+                        (h.saturating_sub(ih) / 2) as isize,
+                        &image,
+                        &opts,
+                        &*st,
+                    );
+                    let frame = f.render_str();
+                    sched.rendered(started.elapsed());
+                    sched.send(frame).await?;
+                    sleep_until(deadline).await;
+                }
+            }
+        }
+        if !played {
+            sched
+                .write("\x1b[0m\x1b[?25h\x1b[?1049lnone of the animations could be loaded\n\r")
+                .await?;
+            sleep(Duration::from_millis(1)).await;
+            sched.close().await;
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gif_loop_count() {
+        let path = std::env::temp_dir().join(format!("weather_ssh-{}.gif", std::process::id()));
+        for (repeat, plays) in [(None, Some(1)), (Some(gif::Repeat::Finite(2)), Some(3)), (Some(gif::Repeat::Infinite), None)] {
+            {
+                let mut encoder = gif::Encoder::new(File::create(&path).unwrap(), 1, 1, &[0, 0, 0, 255, 255, 255]).unwrap();
+                if let Some(repeat) = repeat {
+                    encoder.set_repeat(repeat).unwrap();
+                }
+                encoder.write_frame(&gif::Frame::from_indexed_pixels(1, 1, vec![1], None)).unwrap();
+            }
+            let animation = load_animation(&path).unwrap();
+            assert_eq!((animation.frames.len(), animation.plays), (1, plays), "{repeat:?}");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use crate::frame::{Frame, FromRGB, ToAnsi};
+use crate::layer::Layer;
+use crate::vec3::Vec3;
+
+/// Anything the rasterisers can draw into. `coverage` is how much of the pixel the shape covers, 0..1.
+pub trait Canvas {
+    fn size(&self) -> (usize, usize);
+    fn plot(&mut self, x: i64, y: i64, rgb: [u8; 3], coverage: f32);
+}
+impl Canvas for Layer {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+    fn plot(&mut self, x: i64, y: i64, rgb: [u8; 3], coverage: f32) {
+        if x >= 0 && y >= 0 && coverage > 0.0 {
+            let _ = self.blend_pixel(x as usize, y as usize, rgb, coverage);
+        }
+    }
+}
+/// Draws straight into a `Frame`, quantising every pixel with `FromRGB`.
+/// Partial coverage darkens the colour since texels can't be blended.
+pub struct FrameCanvas<'a, C, ST>
+where
+    C: Clone + PartialEq + ToAnsi,
+{
+    pub frame: &'a mut Frame<C>,
+    pub st: &'a ST,
+}
+impl<C, ST> Canvas for FrameCanvas<'_, C, ST>
+where
+    C: Clone + PartialEq + ToAnsi + FromRGB<ST>,
+{
+    fn size(&self) -> (usize, usize) {
+        (self.frame.width, self.frame.height)
+    }
+    fn plot(&mut self, x: i64, y: i64, rgb: [u8; 3], coverage: f32) {
+        if x >= 0 && y >= 0 && coverage > 0.0 {
+            let c = coverage.min(1.0);
+            let _ = self.frame.set_pixel(
+                x as usize,
+                y as usize,
+                (rgb[0] as f32 * c) as u8,
+                (rgb[1] as f32 * c) as u8,
+                (rgb[2] as f32 * c) as u8,
+                self.st,
+            );
+        }
+    }
+}
+
+fn fpart(x: f64) -> f64 {
+    x - x.floor()
+}
+/// The part of the segment within `x_range` and `y_range` (Liang–Barsky), `None` if there is
+/// none or an end isn't finite.
+fn clip_segment(
+    (x0, y0): (f64, f64),
+    (x1, y1): (f64, f64),
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+) -> Option<(f64, f64, f64, f64)> {
+    if ![x0, y0, x1, y1].iter().all(|v| v.is_finite()) {
+        return None;
+    }
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p, q) in [(-dx, x0 - x_range.0), (dx, x_range.1 - x0), (-dy, y0 - y_range.0), (dy, y_range.1 - y0)] {
+        if p == 0.0 {
+            // parallel to this edge, and outside it
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some((x0 + t0 * dx, y0 + t0 * dy, x0 + t1 * dx, y0 + t1 * dy))
+}
+/// Anti-aliased line (Xiaolin Wu). Pixel centres are at integer coordinates.
+pub fn line(c: &mut impl Canvas, x0: f64, y0: f64, x1: f64, y1: f64, rgb: [u8; 3]) {
+    // steps one pixel at a time, so whatever is off the canvas is cut off first; the margin
+    // keeps the clipped ends' partial coverage off the edge pixels
+    let (w, h) = c.size();
+    let Some((x0, y0, x1, y1)) = clip_segment((x0, y0), (x1, y1), (-2.0, w as f64 + 1.0), (-2.0, h as f64 + 1.0))
+    else {
+        return;
+    };
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    let (mut x0, mut y0, mut x1, mut y1) = if steep {
+        (y0, x0, y1, x1)
+    } else {
+        (x0, y0, x1, y1)
+    };
+    if x0 > x1 {
+        (x0, x1) = (x1, x0);
+        (y0, y1) = (y1, y0);
+    }
+    let mut plot = |x: f64, y: f64, cov: f64| {
+        let (x, y) = if steep { (y, x) } else { (x, y) };
+        c.plot(x as i64, y as i64, rgb, cov as f32);
+    };
+    let dx = x1 - x0;
+    let gradient = if dx == 0.0 { 1.0 } else { (y1 - y0) / dx };
+
+    // first endpoint
+    let xend = x0.round();
+    let yend = y0 + gradient * (xend - x0);
+    let xgap = 1.0 - fpart(x0 + 0.5);
+    let xpxl1 = xend;
+    plot(xpxl1, yend.floor(), (1.0 - fpart(yend)) * xgap);
+    plot(xpxl1, yend.floor() + 1.0, fpart(yend) * xgap);
+    let mut intery = yend + gradient;
+
+    // second endpoint
+    let xend = x1.round();
+    let yend = y1 + gradient * (xend - x1);
+    let xgap = fpart(x1 + 0.5);
+    let xpxl2 = xend;
+    plot(xpxl2, yend.floor(), (1.0 - fpart(yend)) * xgap);
+    plot(xpxl2, yend.floor() + 1.0, fpart(yend) * xgap);
+
+    let mut x = xpxl1 + 1.0;
+    while x < xpxl2 {
+        plot(x, intery.floor(), 1.0 - fpart(intery));
+        plot(x, intery.floor() + 1.0, fpart(intery));
+        intery += gradient;
+        x += 1.0;
+    }
+}
+
+pub fn circle(c: &mut impl Canvas, cx: f64, cy: f64, r: f64, rgb: [u8; 3], filled: bool) {
+    ellipse(c, cx, cy, r, r, rgb, filled)
+}
+/// Anti-aliased ellipse outline (one pixel wide) or filled disc.
+pub fn ellipse(c: &mut impl Canvas, cx: f64, cy: f64, rx: f64, ry: f64, rgb: [u8; 3], filled: bool) {
+    if rx <= 0.0 || ry <= 0.0 {
+        return;
+    }
+    let (w, h) = c.size();
+    let x_min = ((cx - rx - 1.0).floor().max(0.0)) as i64;
+    let x_max = ((cx + rx + 1.0).ceil().min(w as f64 - 1.0)) as i64;
+    let y_min = ((cy - ry - 1.0).floor().max(0.0)) as i64;
+    let y_max = ((cy + ry + 1.0).ceil().min(h as f64 - 1.0)) as i64;
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            let dx = (x as f64 - cx) / rx;
+            let dy = (y as f64 - cy) / ry;
+            // approximate signed distance to the edge, in pixels
+            let d = ((dx * dx + dy * dy).sqrt() - 1.0) * rx.min(ry);
+            let cov = if filled {
+                (0.5 - d).clamp(0.0, 1.0)
+            } else {
+                (1.0 - d.abs()).clamp(0.0, 1.0)
+            };
+            if cov > 0.0 {
+                c.plot(x, y, rgb, cov as f32);
+            }
+        }
+    }
+}
+
+/// Filled triangle, a pixel is drawn when its centre is inside. Only the pixels of its bounding
+/// box that are on the canvas are visited.
+pub fn fill_triangle(c: &mut impl Canvas, p: [(f64, f64); 3], rgb: [u8; 3]) {
+    if !p.iter().all(|p| p.0.is_finite() && p.1.is_finite()) {
+        return;
+    }
+    let (w, h) = c.size();
+    let edge = |a: (f64, f64), b: (f64, f64), x: f64, y: f64| (b.0 - a.0) * (y - a.1) - (b.1 - a.1) * (x - a.0);
+    let area = edge(p[0], p[1], p[2].0, p[2].1);
+    if area == 0.0 {
+        return;
+    }
+    let x_min = p.iter().map(|p| p.0).fold(f64::MAX, f64::min).floor().max(0.0) as i64;
+    let x_max = p.iter().map(|p| p.0).fold(f64::MIN, f64::max).ceil().min(w as f64 - 1.0) as i64;
+    let y_min = p.iter().map(|p| p.1).fold(f64::MAX, f64::min).floor().max(0.0) as i64;
+    let y_max = p.iter().map(|p| p.1).fold(f64::MIN, f64::max).ceil().min(h as f64 - 1.0) as i64;
+    for y in y_min..=y_max {
+        for x in x_min..=x_max {
+            let (fx, fy) = (x as f64, y as f64);
+            let w0 = edge(p[1], p[2], fx, fy) * area.signum();
+            let w1 = edge(p[2], p[0], fx, fy) * area.signum();
+            let w2 = edge(p[0], p[1], fx, fy) * area.signum();
+            if w0 >= 0.0 && w1 >= 0.0 && w2 >= 0.0 {
+                c.plot(x, y, rgb, 1.0);
+            }
+        }
+    }
+}
+/// Filled polygon using the even-odd rule, scanned at pixel centres. Only rows and spans on
+/// the canvas are visited.
+pub fn fill_polygon(c: &mut impl Canvas, points: &[(f64, f64)], rgb: [u8; 3]) {
+    if points.len() < 3 || !points.iter().all(|p| p.0.is_finite() && p.1.is_finite()) {
+        return;
+    }
+    let (w, h) = c.size();
+    let y_min = points.iter().map(|p| p.1).fold(f64::MAX, f64::min).floor().max(0.0) as i64;
+    let y_max = points.iter().map(|p| p.1).fold(f64::MIN, f64::max).ceil().min(h as f64 - 1.0) as i64;
+    let mut xs = Vec::new();
+    for y in y_min..=y_max {
+        let fy = y as f64;
+        xs.clear();
+        for i in 0..points.len() {
+            let a = points[i];
+            let b = points[(i + 1) % points.len()];
+            if (a.1 <= fy) != (b.1 <= fy) {
+                xs.push(a.0 + (fy - a.1) / (b.1 - a.1) * (b.0 - a.0));
+            }
+        }
+        xs.sort_by(f64::total_cmp);
+        for span in xs.chunks_exact(2) {
+            let from = span[0].ceil().max(0.0) as i64;
+            let to = span[1].floor().min(w as f64 - 1.0) as i64;
+            for x in from..=to {
+                c.plot(x, y, rgb, 1.0);
+            }
+        }
+    }
+}
+
+/// Pinhole camera `weather` looks through: +z is forward, +y is down.
+pub struct Camera {
+    pub origin: Vec3,
+    pub top_left: Vec3,
+    pub right: Vec3,
+    pub down: Vec3,
+    pub width: usize,
+    pub height: usize,
+}
+impl Camera {
+    pub fn new(width: usize, height: usize) -> Camera {
+        let camplanez = 1.0;
+        let top_left = Vec3::new(-(width as f64 / height as f64), -1.0, camplanez);
+        Camera {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            top_left,
+            right: Vec3::new(-2.0 * top_left.c[0], 0.0, 0.0),
+            down: Vec3::new(0.0, -2.0 * top_left.c[1], 0.0),
+            width,
+            height,
+        }
+    }
+    /// View direction through screen position (x, y), not normalized.
+    pub fn ray(&self, x: f64, y: f64) -> Vec3 {
+        self.top_left + self.right * (x / self.width as f64) + self.down * (y / self.height as f64)
+    }
+    pub fn forward(&self) -> Vec3 {
+        (self.top_left + self.right * 0.5 + self.down * 0.5).normalize()
+    }
+    /// Screen position and view depth of `p`, `None` if it is behind the camera.
+    pub fn project(&self, p: Vec3) -> Option<(f64, f64, f64)> {
+        let q = p - self.origin;
+        let depth = q.dot(&self.forward());
+        if depth <= 0.0 {
+            return None;
+        }
+        let lcomb = (self.top_left - q).lin_comb(-self.right, -self.down, -q);
+        Some((
+            lcomb.c[0] * self.width as f64,
+            lcomb.c[1] * self.height as f64,
+            depth,
+        ))
+    }
+    /// Cuts the segment a-b at the near plane so both ends can be projected.
+    fn clip(&self, a: Vec3, b: Vec3) -> Option<(Vec3, Vec3)> {
+        const NEAR: f64 = 0.01;
+        let f = self.forward();
+        let da = (a - self.origin).dot(&f);
+        let db = (b - self.origin).dot(&f);
+        match (da > NEAR, db > NEAR) {
+            (true, true) => Some((a, b)),
+            (false, false) => None,
+            (true, false) => Some((a, a + (b - a) * ((da - NEAR) / (da - db)))),
+            (false, true) => Some((b + (a - b) * ((db - NEAR) / (db - da)), b)),
+        }
+    }
+}
+pub fn line_3d(c: &mut impl Canvas, cam: &Camera, a: Vec3, b: Vec3, rgb: [u8; 3]) {
+    if let Some((a, b)) = cam.clip(a, b)
+        && let (Some(pa), Some(pb)) = (cam.project(a), cam.project(b))
+    {
+        line(c, pa.0, pa.1, pb.0, pb.1, rgb);
+    }
+}
+/// Filled triangle; skipped entirely if any corner is behind the camera.
+pub fn fill_triangle_3d(c: &mut impl Canvas, cam: &Camera, p: [Vec3; 3], rgb: [u8; 3]) {
+    if let (Some(a), Some(b), Some(d)) = (cam.project(p[0]), cam.project(p[1]), cam.project(p[2])) {
+        fill_triangle(c, [(a.0, a.1), (b.0, b.1), (d.0, d.1)], rgb);
+    }
+}
+pub fn fill_polygon_3d(c: &mut impl Canvas, cam: &Camera, points: &[Vec3], rgb: [u8; 3]) {
+    let projected: Option<Vec<(f64, f64)>> = points
+        .iter()
+        .map(|&p| cam.project(p).map(|s| (s.0, s.1)))
+        .collect();
+    if let Some(projected) = projected {
+        fill_polygon(c, &projected, rgb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// Remembers the coverage plotted at each pixel, on or off the canvas, and how many
+    /// plots there were.
+    struct Plots {
+        size: (usize, usize),
+        pixels: HashMap<(i64, i64), f32>,
+        calls: usize,
+    }
+    impl Plots {
+        fn new(w: usize, h: usize) -> Plots {
+            Plots { size: (w, h), pixels: HashMap::new(), calls: 0 }
+        }
+        fn covered(&self) -> Vec<(i64, i64)> {
+            let mut covered: Vec<_> = self.pixels.iter().filter(|(_, c)| **c > 0.0).map(|(p, _)| *p).collect();
+            covered.sort();
+            covered
+        }
+    }
+    impl Canvas for Plots {
+        fn size(&self) -> (usize, usize) {
+            self.size
+        }
+        fn plot(&mut self, x: i64, y: i64, _: [u8; 3], coverage: f32) {
+            self.calls += 1;
+            *self.pixels.entry((x, y)).or_default() += coverage;
+        }
+    }
+    const WHITE: [u8; 3] = [255; 3];
+
+    #[test]
+    fn line_endpoints() {
+        let mut c = Plots::new(10, 10);
+        line(&mut c, 1.0, 2.0, 6.0, 2.0, WHITE);
+        assert_eq!(c.covered(), (1..=6).map(|x| (x, 2)).collect::<Vec<_>>());
+        // integer ends cover half their pixel, the rest is solid
+        assert_eq!(c.pixels[&(1, 2)], 0.5);
+        assert_eq!(c.pixels[&(3, 2)], 1.0);
+        // steep lines, and lines drawn backwards, end at the same pixels
+        let mut c = Plots::new(10, 10);
+        line(&mut c, 4.0, 8.0, 4.0, 3.0, WHITE);
+        assert_eq!(c.covered(), (3..=8).map(|y| (4, y)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn line_clipped() {
+        let mut c = Plots::new(10, 10);
+        line(&mut c, -1e12, 2.0, 1e12, 2.0, WHITE);
+        assert!(c.calls < 40, "{} plots", c.calls);
+        for x in 0..10 {
+            assert_eq!(c.pixels[&(x, 2)], 1.0, "{x}");
+        }
+        let mut c = Plots::new(10, 10);
+        line(&mut c, -1e9, -1e9, 1e9, 1e9, WHITE);
+        assert!(c.calls < 40, "{} plots", c.calls);
+        for i in 0..10 {
+            assert_eq!(c.pixels[&(i, i)], 1.0, "{i}");
+        }
+        // nothing of these is on the canvas
+        let off = [
+            (-1e9, -5.0, 1e9, -5.0),
+            (20.0, 0.0, 30.0, 9.0),
+            (f64::NAN, 0.0, 5.0, 5.0),
+            (0.0, 0.0, f64::INFINITY, 0.0),
+        ];
+        for (x0, y0, x1, y1) in off {
+            let mut c = Plots::new(10, 10);
+            line(&mut c, x0, y0, x1, y1, WHITE);
+            let visible = c.covered().into_iter().filter(|&(x, y)| (0..10).contains(&x) && (0..10).contains(&y));
+            assert_eq!(visible.count(), 0, "{x0} {y0} {x1} {y1}");
+            assert!(c.calls < 10, "{} plots", c.calls);
+        }
+    }
+
+    #[test]
+    fn triangle_coverage() {
+        let mut c = Plots::new(10, 10);
+        fill_triangle(&mut c, [(0.0, 0.0), (8.0, 0.0), (0.0, 8.0)], WHITE);
+        // the centres with x + y <= 8, edges included
+        assert_eq!(c.covered().len(), 45);
+        assert!(c.covered().iter().all(|&(x, y)| x + y <= 8));
+        // the winding doesn't matter
+        let mut back = Plots::new(10, 10);
+        fill_triangle(&mut back, [(0.0, 8.0), (8.0, 0.0), (0.0, 0.0)], WHITE);
+        assert_eq!(back.covered(), c.covered());
+
+        let mut c = Plots::new(10, 10);
+        fill_triangle(&mut c, [(-1e12, -1e12), (1e12, -1e12), (0.0, 1e12)], WHITE);
+        assert_eq!((c.covered().len(), c.calls), (100, 100));
+        let mut c = Plots::new(10, 10);
+        fill_triangle(&mut c, [(f64::NAN, 0.0), (8.0, 0.0), (0.0, 8.0)], WHITE);
+        assert_eq!(c.calls, 0);
+    }
+
+    #[test]
+    fn polygon_coverage() {
+        let mut c = Plots::new(10, 10);
+        fill_polygon(&mut c, &[(-1e12, -1e12), (1e12, -1e12), (1e12, 1e12), (-1e12, 1e12)], WHITE);
+        assert_eq!((c.covered().len(), c.calls), (100, 100));
+        let mut c = Plots::new(10, 10);
+        fill_polygon(&mut c, &[(0.0, 0.0), (5.0, f64::NAN), (5.0, 5.0)], WHITE);
+        assert_eq!(c.calls, 0);
+    }
+}
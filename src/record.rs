@@ -0,0 +1,357 @@
+//! Recordings of what viewers see, as asciicast v2 files that `asciinema play` can replay.
+//!
+//! With `WEATHER_SSH_RECORD` set to a directory, 1 in `WEATHER_SSH_RECORD_SAMPLE` terminal
+//! sessions is recorded there: everything the app sends, and the window's size as it
+//! changes. A recording stops at `WEATHER_SSH_RECORD_MAX_BYTES`, and the oldest recordings are
+//! deleted to keep the directory under `WEATHER_SSH_RECORD_MAX_TOTAL`. Help sessions show
+//! the username, so they are only recorded when identities may be logged.
+//!
+//! `weather_ssh record` records a headless run of an app instead, in builds with the `headless`
+//! feature.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::time::Instant;
+
+use crate::config::config;
+use crate::headless::{Cli, block_on_paused};
+use crate::logging::{self, Level, json_str};
+
+const USAGE: &str = "usage: weather_ssh record [--out FILE] [--size COLSxROWS] [--seconds N] <app> [app options]
+
+Runs <app> without a client for N seconds (default 10) in a COLSxROWS terminal (default
+80x24) and writes what it shows to FILE (default <app>-<seed>.cast) as an asciicast v2
+recording. The run goes by the app's own clock, so it takes far less than N seconds. App
+options come after the app's name, e.g. `weather_ssh record weather --intensity 2 --seed 7`.";
+
+/// Events queued for a recording's writer before the recording is given up on.
+const QUEUE: usize = 256;
+
+/// A recording being written. The file is created and written by a thread of its own, so
+/// sessions never wait on the disk.
+pub struct Recorder {
+    /// None once the recording has stopped.
+    queue: Mutex<Option<SyncSender<Job>>>,
+    /// Set by the writer when it stops on its own.
+    stopped: Arc<AtomicBool>,
+    path: PathBuf,
+    start: Instant,
+}
+enum Job {
+    Line(String),
+    /// Write out what is buffered and say how that went.
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+impl Recorder {
+    /// Starts writing to the file `open` returns, beginning with the header for a `cols`x`rows`
+    /// terminal. `open` runs on the writer's thread; if it fails, nothing is recorded and
+    /// `flush` says why.
+    pub fn create(
+        path: &Path,
+        open: impl FnOnce() -> io::Result<File> + Send + 'static,
+        header: Header,
+        max_bytes: u64,
+    ) -> io::Result<Self> {
+        let (queue, events) = mpsc::sync_channel(QUEUE);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let writer = Writer {
+            path: path.to_path_buf(),
+            stopped: stopped.clone(),
+            written: 0,
+            max_bytes,
+        };
+        thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || writer.run(open, header, events))?;
+        Ok(Recorder {
+            queue: Mutex::new(Some(queue)),
+            stopped,
+            path: path.to_path_buf(),
+            start: Instant::now(),
+        })
+    }
+    /// Records `text` as sent to the terminal.
+    pub fn output(&self, text: &str) {
+        self.event("o", text);
+    }
+    pub fn resize(&self, cols: u32, rows: u32) {
+        self.event("r", &format!("{cols}x{rows}"));
+    }
+    fn event(&self, kind: &str, data: &str) {
+        let mut queue = self.queue.lock().unwrap();
+        let Some(q) = queue.as_ref() else {
+            return;
+        };
+        if self.stopped.load(Ordering::Relaxed) {
+            *queue = None;
+            return;
+        }
+        let line = format!("[{:.6},\"{kind}\",{}]\n", self.start.elapsed().as_secs_f64(), json_str(data));
+        match q.try_send(Job::Line(line)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                // a recording with frames missing is worse than one that ends early
+                logging::event(Level::Warn, "recording stopped, the disk can't keep up", &[("path", self.path.display().to_string().into())]);
+                *queue = None;
+            }
+            Err(TrySendError::Disconnected(_)) => *queue = None,
+        }
+    }
+    /// Waits for everything recorded so far to be written out. Blocks, so not for sessions.
+    pub fn flush(&self) -> io::Result<()> {
+        let (reply, result) = mpsc::channel();
+        let Some(q) = self.queue.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        if q.send(Job::Flush(reply)).is_err() {
+            return Ok(());
+        }
+        result.recv().unwrap_or(Ok(()))
+    }
+}
+
+/// What the first line of a recording says.
+pub struct Header {
+    pub cols: u32,
+    pub rows: u32,
+    pub term: String,
+    pub title: String,
+}
+/// The writing end of a recording.
+struct Writer {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+    written: u64,
+    max_bytes: u64,
+}
+impl Writer {
+    fn run(mut self, open: impl FnOnce() -> io::Result<File>, header: Header, events: Receiver<Job>) {
+        let result = open().map(BufWriter::new).and_then(|mut out| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let header = format!(
+                "{{\"version\":2,\"width\":{},\"height\":{},\"timestamp\":{},\"title\":{},\"env\":{{\"TERM\":{}}}}}\n",
+                header.cols,
+                header.rows,
+                now.as_secs(),
+                json_str(&header.title),
+                json_str(&header.term)
+            );
+            out.write_all(header.as_bytes())?;
+            self.written = header.len() as u64;
+            self.write(&mut out, &events)
+        });
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Err(e) = &result {
+            logging::event(
+                Level::Warn,
+                "could not write recording",
+                &[("path", self.path.display().to_string().into()), ("error", e.to_string().into())],
+            );
+        }
+        // whoever still flushes hears how it ended
+        for event in events {
+            if let Job::Flush(reply) = event {
+                let _ = reply.send(result.as_ref().map(|_| ()).map_err(|e| io::Error::new(e.kind(), e.to_string())));
+            }
+        }
+    }
+    /// Writes events until the recording is dropped or reaches its size limit.
+    fn write(&mut self, out: &mut BufWriter<File>, events: &Receiver<Job>) -> io::Result<()> {
+        for event in events {
+            match event {
+                Job::Line(line) => {
+                    if self.written + line.len() as u64 > self.max_bytes {
+                        logging::event(Level::Info, "recording stopped at its size limit", &[("path", self.path.display().to_string().into())]);
+                        break;
+                    }
+                    out.write_all(line.as_bytes())?;
+                    self.written += line.len() as u64;
+                }
+                Job::Flush(reply) => {
+                    let _ = reply.send(out.flush());
+                }
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Starts recording a terminal session of `app` if recording is on and it's this session's
+/// turn. Room is made in the directory, and the file created, on the recording's own thread.
+pub fn for_session(id: u64, app: &str, seed: Option<u64>, term: &str, cols: u32, rows: u32) -> Option<Arc<Recorder>> {
+    static SESSIONS: AtomicU64 = AtomicU64::new(0);
+    let settings = config();
+    let dir = settings.record_dir.clone()?;
+    if app == "help" && !settings.log_identity {
+        return None;
+    }
+    if !SESSIONS.fetch_add(1, Ordering::Relaxed).is_multiple_of(settings.record_sample) {
+        return None;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let path = dir.join(format!("{}-{id}-{app}.cast", now.as_secs()));
+    let open = {
+        let path = path.clone();
+        move || {
+            fs::create_dir_all(&dir)?;
+            prune(&dir, settings.record_max_total);
+            File::create(&path)
+        }
+    };
+    let header = Header {
+        cols,
+        rows,
+        term: term.to_string(),
+        title: title(app, seed),
+    };
+    let path_field = ("path", path.display().to_string().into());
+    match Recorder::create(&path, open, header, settings.record_max_bytes) {
+        Ok(recorder) => {
+            logging::event(Level::Info, "recording started", &[("session", id.into()), path_field]);
+            Some(Arc::new(recorder))
+        }
+        Err(e) => {
+            logging::event(Level::Warn, "could not start recording", &[path_field, ("error", e.to_string().into())]);
+            None
+        }
+    }
+}
+fn title(app: &str, seed: Option<u64>) -> String {
+    match seed {
+        Some(seed) => format!("{app} --seed {seed}"),
+        None => app.to_string(),
+    }
+}
+
+/// Deletes the oldest recordings in `dir` until the rest take up less than `max_total` bytes.
+fn prune(dir: &Path, max_total: u64) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut casts: Vec<(SystemTime, u64, PathBuf)> = entries
+        .flatten()
+        .filter(|e| e.path().extension().is_some_and(|x| x == "cast"))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            Some((meta.modified().unwrap_or(UNIX_EPOCH), meta.len(), e.path()))
+        })
+        .collect();
+    casts.sort();
+    let mut total: u64 = casts.iter().map(|c| c.1).sum();
+    for (_, len, path) in casts {
+        if total < max_total {
+            break;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                total -= len;
+                logging::event(Level::Debug, "recording deleted", &[("path", path.display().to_string().into())]);
+            }
+            Err(e) => logging::event(
+                Level::Warn,
+                "could not delete recording",
+                &[("path", path.display().to_string().into()), ("error", e.to_string().into())],
+            ),
+        }
+    }
+}
+
+/// `weather_ssh record ...`, `args` starts after the subcommand.
+pub fn run(args: &[String]) -> ExitCode {
+    let mut cli = match Cli::parse(args, USAGE) {
+        Ok(cli) => cli,
+        Err(code) => return code,
+    };
+    let (app, seed) = (cli.run.app, cli.seed());
+    let path = cli.out.take().unwrap_or_else(|| PathBuf::from(format!("{app}-{seed}.cast")));
+    let header = Header {
+        cols: cli.run.cols,
+        rows: cli.run.rows,
+        term: "xterm-256color".to_string(),
+        title: title(app, Some(seed)),
+    };
+    let open = {
+        let path = path.clone();
+        move || File::create(path)
+    };
+    let recorder = match Recorder::create(&path, open, header, u64::MAX) {
+        Ok(r) => Arc::new(r),
+        Err(e) => {
+            eprintln!("could not create {}: {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    cli.run.recorder = Some(recorder.clone());
+    if let Err(code) = block_on_paused(cli.run.run(cli.ticks())) {
+        return code;
+    }
+    if let Err(e) = recorder.flush() {
+        eprintln!("could not write {}: {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+    eprintln!("wrote {} ({}s of {app}, seed {seed})", path.display(), cli.seconds);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::Headless;
+    use crate::vt::Screen;
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_to_the_same_screen() {
+        let path = std::env::temp_dir().join(format!("weather_ssh-{}.cast", std::process::id()));
+        let header = Header {
+            cols: 40,
+            rows: 10,
+            term: "xterm".to_string(),
+            title: "virus".to_string(),
+        };
+        let open = {
+            let path = path.clone();
+            move || File::create(path)
+        };
+        let recorder = Arc::new(Recorder::create(&path, open, header, u64::MAX).unwrap());
+        let run = Headless::new("virus", 40, 10, 3).with_recorder(recorder.clone());
+        let expected = run.screen(100).await.text();
+        recorder.flush().unwrap();
+        let cast = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut lines = cast.lines();
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("{\"version\":2,\"width\":40,\"height\":10,"), "{header}");
+        // undoes json_str, enough for what the apps send
+        let unescape = |s: &str| {
+            s.replace("\\u001b", "\x1b")
+                .replace("\\r", "\r")
+                .replace("\\n", "\n")
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\")
+        };
+        let mut screen = Screen::new(40, 10);
+        let mut last = 0.0;
+        for line in lines {
+            let (time, rest) = line.strip_prefix('[').unwrap().split_once(",\"o\",\"").unwrap();
+            let time: f64 = time.parse().unwrap();
+            assert!(time >= last, "{line}");
+            last = time;
+            screen.feed(unescape(rest.strip_suffix("\"]").unwrap()).as_bytes());
+        }
+        assert!(last > 1.0);
+        assert_eq!(screen.text(), expected);
+    }
+}
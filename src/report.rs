@@ -0,0 +1,190 @@
+//! What sessions without a terminal get: a one-shot report as plain text or JSON, then an
+//! exit status, so the server can be used from scripts.
+
+use std::{fmt::Write as _, time::Duration};
+
+use rand::Rng;
+use russh::{ChannelId, CryptoVec, server::Handle};
+
+use crate::args::Args;
+
+use crate::logging::json_str;
+use crate::messages::{FAILURE_CHANCE, generate_message};
+use crate::seed;
+use crate::world::{self, Scene, WorldState, sim_time, wind_at};
+
+/// How far ahead the forecast looks.
+const FORECAST: [Duration; 3] = [
+    Duration::from_secs(15 * 60),
+    Duration::from_secs(60 * 60),
+    Duration::from_secs(3 * 60 * 60),
+];
+/// Messages `virus` prints unless told otherwise.
+const DEFAULT_MESSAGES: u64 = 10;
+/// Most messages `virus` prints, however many are asked for; they're all built in memory.
+pub const MAX_MESSAGES: u64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Wind as (east, north) in metres per second.
+type Wind = (f64, f64);
+
+pub struct WeatherReport {
+    pub seed: u64,
+    pub snowflakes: usize,
+    pub wind: Wind,
+    pub forecast: Vec<(Duration, Wind)>,
+}
+impl WeatherReport {
+    pub fn new(state: &WorldState) -> Self {
+        WeatherReport {
+            seed: state.seed,
+            snowflakes: state.particles.len(),
+            wind: wind_at(state.noise, state.t),
+            // the wind is noise over time, so the forecast is never wrong
+            forecast: FORECAST
+                .iter()
+                .map(|&ahead| (ahead, wind_at(state.noise, state.t + sim_time(ahead))))
+                .collect(),
+        }
+    }
+    pub fn text(&self) -> String {
+        let mut out = format!(
+            "Now: {}, {}\n",
+            snowfall(self.snowflakes),
+            describe_wind(self.wind)
+        );
+        for (ahead, wind) in &self.forecast {
+            let _ = writeln!(out, "In {}: {}", describe_duration(*ahead), describe_wind(*wind));
+        }
+        out
+    }
+    pub fn json(&self) -> String {
+        let wind = |w: Wind| {
+            format!(
+                "\"wind_speed\":{:.1},\"wind_from\":{},\"wind\":{}",
+                speed(w),
+                json_str(compass(w)),
+                json_str(beaufort(speed(w)))
+            )
+        };
+        let forecast: Vec<String> = self
+            .forecast
+            .iter()
+            .map(|(ahead, w)| format!("{{\"in_seconds\":{},{}}}", ahead.as_secs(), wind(*w)))
+            .collect();
+        format!(
+            "{{\"seed\":{},\"snowflakes\":{},\"snowfall\":{},{},\"forecast\":[{}]}}\n",
+            self.seed,
+            self.snowflakes,
+            json_str(snowfall(self.snowflakes)),
+            wind(self.wind),
+            forecast.join(",")
+        )
+    }
+}
+
+fn speed(w: Wind) -> f64 {
+    w.0.hypot(w.1)
+}
+/// Beaufort scale name of a wind speed.
+fn beaufort(speed: f64) -> &'static str {
+    match speed {
+        s if s < 0.5 => "calm",
+        s if s < 1.6 => "light air",
+        s if s < 3.4 => "light breeze",
+        s if s < 5.5 => "gentle breeze",
+        s if s < 8.0 => "moderate breeze",
+        s if s < 10.8 => "fresh breeze",
+        _ => "strong wind",
+    }
+}
+/// The compass point the wind blows from.
+fn compass(w: Wind) -> &'static str {
+    const POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+    // bearing of where it comes from, clockwise from north
+    let bearing = (-w.0).atan2(-w.1).to_degrees().rem_euclid(360.0);
+    POINTS[((bearing + 22.5) / 45.0) as usize % 8]
+}
+fn snowfall(snowflakes: usize) -> &'static str {
+    match snowflakes {
+        0 => "no snow",
+        n if n < 500 => "light snow",
+        n if n < 1500 => "snow",
+        _ => "heavy snow",
+    }
+}
+fn describe_wind(w: Wind) -> String {
+    format!("{} from the {}, {:.1} m/s", beaufort(speed(w)), compass(w), speed(w))
+}
+fn describe_duration(d: Duration) -> String {
+    let minutes = d.as_secs() / 60;
+    if minutes < 60 {
+        format!("{minutes} minutes")
+    } else if minutes == 60 {
+        "1 hour".to_string()
+    } else {
+        format!("{} hours", minutes / 60)
+    }
+}
+
+/// One-shot output of an app with its options, run on `channel` without a terminal. Returns
+/// the exit status.
+pub async fn exec(session: &Handle, channel: ChannelId, app: &str, args: &Args, help: String) -> u32 {
+    let out = |text: String| async { session.data(channel, CryptoVec::from(text)).await.is_ok() };
+    let format = if args.flag("json") { Format::Json } else { Format::Text };
+    match app {
+        "weather" => {
+            let report = WeatherReport::new(&*world::snapshot(&scene(args)).await);
+            out(match format {
+                Format::Text => report.text(),
+                Format::Json => report.json(),
+            })
+            .await;
+        }
+        "virus" => {
+            let count = args.int("count").unwrap_or(DEFAULT_MESSAGES).min(MAX_MESSAGES);
+            let mut rng = seed::rng_from(args.int("seed").unwrap_or_else(seed::random));
+            let messages: Vec<(String, bool)> = (0..count)
+                .map(|_| (generate_message(&mut rng), !(args.flag("errors") && rng.random_bool(FAILURE_CHANCE))))
+                .collect();
+            out(match format {
+                Format::Text => messages
+                    .iter()
+                    .map(|(m, ok)| format!("{m}...  {}\n", if *ok { "done!" } else { "failed!" }))
+                    .collect(),
+                Format::Json => format!(
+                    "[{}]\n",
+                    messages
+                        .iter()
+                        .map(|(m, ok)| format!("{{\"message\":{},\"ok\":{ok}}}", json_str(m)))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            })
+            .await;
+        }
+        "gif" => {
+            let _ = session
+                .extended_data(channel, 1, CryptoVec::from("gif needs a terminal, try `ssh -t`\n"))
+                .await;
+            return 1;
+        }
+        _ => {
+            out(help).await;
+        }
+    }
+    0
+}
+
+/// The weather scene `args` ask for.
+pub fn scene(args: &Args) -> Scene {
+    Scene {
+        intensity: args.float("intensity").unwrap_or(1.0),
+        seed: args.int("seed"),
+    }
+}
@@ -0,0 +1,264 @@
+//! Frame pacing. Every session sends frames through a `FrameScheduler`, which holds them back
+//! when the client isn't reading them, and all sessions share a CPU budget that lowers
+//! rendering quality when the server as a whole falls behind.
+
+use std::{
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering},
+    },
+    thread::available_parallelism,
+    time::Duration,
+};
+
+use russh::{ChannelId, CryptoVec, server::Handle};
+use tokio::time::{Instant, sleep_until};
+
+use crate::config::config;
+use crate::metrics::AppMetrics;
+use crate::record::Recorder;
+
+/// Slowest the frame rate adapts down to.
+const MAX_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the shared CPU load is re-measured.
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// What the client last said about its receive window, see `Handler::window_adjusted`.
+#[derive(Default)]
+pub struct ClientWindow {
+    size: AtomicU32,
+    /// Number of reports so far, since a report may repeat the previous size.
+    updates: AtomicU32,
+}
+impl ClientWindow {
+    pub fn update(&self, size: u32) {
+        self.size.store(size, Ordering::Relaxed);
+        self.updates.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Where a scheduler's output goes.
+pub enum Sink {
+    Channel(Handle, ChannelId),
+    /// A buffer, for running apps without a client.
+    Capture(Arc<Mutex<Vec<u8>>>),
+}
+impl Sink {
+    async fn data(&self, text: String) -> Result<(), CryptoVec> {
+        match self {
+            Sink::Channel(session, channel) => session.data(*channel, CryptoVec::from(text)).await,
+            Sink::Capture(buf) => {
+                buf.lock().unwrap().extend_from_slice(text.as_bytes());
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct FrameScheduler {
+    sink: Sink,
+    window: Arc<ClientWindow>,
+    /// Bytes sent since the window was last reported.
+    unacked: usize,
+    last_update: u32,
+    target: Duration,
+    interval: Duration,
+    next: Instant,
+    /// Smoothed time `session.data` takes to accept a frame.
+    latency: Duration,
+    /// Total bytes sent, shared with the session's log span.
+    sent: Arc<AtomicU64>,
+    metrics: &'static AppMetrics,
+    /// Overrides the shared quality, so headless runs don't depend on the machine's load.
+    quality: Option<Quality>,
+    /// Gets a copy of everything sent.
+    recorder: Option<Arc<Recorder>>,
+}
+impl FrameScheduler {
+    pub fn new(
+        session: Handle,
+        channel: ChannelId,
+        window: Arc<ClientWindow>,
+        sent: Arc<AtomicU64>,
+        metrics: &'static AppMetrics,
+    ) -> Self {
+        Self::with_sink(Sink::Channel(session, channel), window, sent, metrics)
+    }
+    /// A scheduler writing into `capture` at full quality.
+    pub fn headless(capture: Arc<Mutex<Vec<u8>>>, metrics: &'static AppMetrics) -> Self {
+        let mut sched = Self::with_sink(Sink::Capture(capture), Arc::default(), Arc::default(), metrics);
+        sched.quality = Some(Quality::Full);
+        sched
+    }
+    fn with_sink(sink: Sink, window: Arc<ClientWindow>, sent: Arc<AtomicU64>, metrics: &'static AppMetrics) -> Self {
+        let target = Duration::from_secs_f64(1.0 / config().fps);
+        FrameScheduler {
+            sink,
+            last_update: 0,
+            window,
+            unacked: 0,
+            target,
+            interval: target,
+            next: Instant::now(),
+            latency: Duration::ZERO,
+            sent,
+            metrics,
+            quality: None,
+            recorder: None,
+        }
+    }
+    /// Tees everything sent from now on into `recorder`.
+    pub fn record_to(&mut self, recorder: Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+    /// Aims for `fps` instead of the server's frame rate, if that is lower.
+    pub fn set_fps(&mut self, fps: f64) {
+        self.target = Duration::from_secs_f64(1.0 / fps.min(config().fps));
+        self.interval = self.target;
+    }
+    /// Time between frames right now, including the slowdown from the CPU budget.
+    pub fn interval(&self) -> Duration {
+        match self.quality() {
+            Quality::Minimal => (self.interval * 2).min(MAX_INTERVAL),
+            _ => self.interval,
+        }
+    }
+    /// The quality to render at.
+    pub fn quality(&self) -> Quality {
+        self.quality.unwrap_or_else(quality)
+    }
+    /// Waits until the next frame is due. Slots that passed while rendering or sending
+    /// are dropped instead of being made up for with a burst of frames.
+    pub async fn tick(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            sleep_until(self.next).await;
+            self.next += self.interval();
+        } else {
+            self.next = now + self.interval();
+        }
+    }
+    /// Whether the client is keeping up: unknown windows count as open, and a frame
+    /// bigger than the whole window still goes through when nothing is outstanding.
+    fn congested(&mut self, bytes: usize) -> bool {
+        let updates = self.window.updates.load(Ordering::Acquire);
+        if updates != self.last_update {
+            self.last_update = updates;
+            self.unacked = 0;
+        }
+        let window = self.window.size.load(Ordering::Relaxed) as usize;
+        updates != 0 && self.unacked > 0 && self.unacked + bytes > window
+    }
+    /// Sends `frame` unless the client is behind, in which case it is dropped and the frame
+    /// rate backs off. Returns whether it was sent.
+    pub async fn send(&mut self, frame: String) -> Result<bool, CryptoVec> {
+        if self.congested(frame.len()) {
+            self.interval = (self.interval * 2).min(MAX_INTERVAL);
+            self.metrics.dropped.inc();
+            return Ok(false);
+        }
+        self.unacked += frame.len();
+        self.count_sent(frame.len());
+        self.metrics.frames.inc();
+        self.tee(&frame);
+        let start = Instant::now();
+        self.sink.data(frame).await?;
+        self.latency = (self.latency * 3 + start.elapsed()) / 4;
+        if self.latency > self.interval / 2 {
+            self.interval = (self.interval * 2).min(MAX_INTERVAL);
+        } else {
+            // creep back up to the target rate
+            self.interval = (self.interval * 7 / 8).max(self.target);
+        }
+        Ok(true)
+    }
+    /// Sends `text` right away, for output that can't be dropped like a frame can.
+    pub async fn write(&mut self, text: impl Into<String>) -> Result<(), CryptoVec> {
+        let text = text.into();
+        self.unacked += text.len();
+        self.count_sent(text.len());
+        self.tee(&text);
+        self.sink.data(text).await
+    }
+    fn tee(&self, text: &str) {
+        if let Some(r) = &self.recorder {
+            r.output(text);
+        }
+    }
+    fn count_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.metrics.bytes.add(bytes as u64);
+    }
+    /// Counts `spent` rendering a frame against the CPU budget and in the render time metrics.
+    pub fn rendered(&self, spent: Duration) {
+        record_cpu(spent);
+        self.metrics.render.observe(spent);
+    }
+    /// Counts a frame the app skipped on its own, e.g. to catch up with an animation's clock.
+    pub fn skipped(&self) {
+        self.metrics.dropped.inc();
+    }
+    /// Closes the channel.
+    pub async fn close(&self) {
+        if let Sink::Channel(session, channel) = &self.sink {
+            let _ = session.close(*channel).await;
+        }
+    }
+}
+
+/// How much detail rendering can afford under the current server load.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Quality {
+    Full,
+    /// No sub-cell shape matching.
+    Reduced,
+    /// Also half the frame rate.
+    Minimal,
+}
+
+struct Budget {
+    busy_ns: AtomicU64,
+    window_start: Mutex<std::time::Instant>,
+    quality: AtomicU8,
+}
+fn budget() -> &'static Budget {
+    static BUDGET: OnceLock<Budget> = OnceLock::new();
+    BUDGET.get_or_init(|| Budget {
+        busy_ns: AtomicU64::new(0),
+        window_start: Mutex::new(std::time::Instant::now()),
+        quality: AtomicU8::new(Quality::Full as u8),
+    })
+}
+
+/// Counts `spent` (rendering or simulating) against the shared CPU budget.
+pub fn record_cpu(spent: Duration) {
+    let b = budget();
+    b.busy_ns.fetch_add(spent.as_nanos() as u64, Ordering::Relaxed);
+    let mut start = b.window_start.lock().unwrap();
+    let elapsed = start.elapsed();
+    if elapsed < LOAD_WINDOW {
+        return;
+    }
+    *start = std::time::Instant::now();
+    let busy = b.busy_ns.swap(0, Ordering::Relaxed) as f64 / 1e9;
+    let cores = available_parallelism().map(|n| n.get()).unwrap_or(1) as f64;
+    let load = busy / (elapsed.as_secs_f64() * cores * config().cpu_budget);
+    let q = b.quality.load(Ordering::Relaxed);
+    // step down as soon as the budget is exceeded, but only back up with room to spare,
+    // since going up costs a lot more than the headroom that triggered it
+    let q = if load > 1.0 {
+        (q + 1).min(Quality::Minimal as u8)
+    } else if load < 0.4 {
+        q.saturating_sub(1)
+    } else {
+        q
+    };
+    b.quality.store(q, Ordering::Relaxed);
+}
+pub fn quality() -> Quality {
+    match budget().quality.load(Ordering::Relaxed) {
+        0 => Quality::Full,
+        1 => Quality::Reduced,
+        _ => Quality::Minimal,
+    }
+}
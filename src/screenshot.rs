@@ -0,0 +1,246 @@
+//! Pictures of a terminal screen, for docs and for diffing how scenes look: a PNG drawn with
+//! the bitmap font from font.txt, or an SVG with the text as text. Colours come from the
+//! palette in use, the way a terminal set up with it would show them.
+//!
+//! Anything that writes to a terminal can be pictured by feeding it through a `vt::Screen`,
+//! a `Frame` included (`Screen::feed(frame.render_str().as_bytes())`).
+
+use std::{fmt::Write as _, fs, path::PathBuf, process::ExitCode};
+
+use image::{Rgb, RgbImage};
+
+use crate::headless::{Cli, block_on_paused};
+use crate::termdata::{BitmapFont, TerminalData, load_font, read_term_data};
+use crate::vt::{Cell, Color, Screen};
+
+const USAGE: &str = "usage: weather_ssh screenshot [--out FILE] [--size COLSxROWS] [--seconds N] <app> [app options]
+
+Runs <app> without a client for N seconds (default 10) in a COLSxROWS terminal (default
+80x24) and draws the screen it ends up with to FILE (default <app>-<seed>.png), as a PNG or,
+if FILE ends in .svg, as an SVG. App options come after the app's name, e.g.
+`weather_ssh screenshot --out docs/weather.svg weather --seed 7`.";
+
+/// Levels of the 6x6x6 cube in the 256 colour palette, as xterm draws them.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// What `color` looks like, with `default` standing in for the terminal's own colour.
+fn rgb(color: Color, default: [u8; 3], st: &TerminalData) -> [u8; 3] {
+    match color {
+        Color::Default => default,
+        Color::Indexed(i @ 0..16) => st.palette[i as usize],
+        Color::Indexed(i @ 16..232) => {
+            let i = i - 16;
+            [CUBE_LEVELS[(i / 36) as usize], CUBE_LEVELS[(i / 6 % 6) as usize], CUBE_LEVELS[(i % 6) as usize]]
+        }
+        Color::Indexed(i) => [8 + 10 * (i - 232); 3],
+        Color::Rgb(r, g, b) => [r, g, b],
+    }
+}
+/// Foreground and background of `cell`.
+fn cell_colors(cell: &Cell, st: &TerminalData) -> ([u8; 3], [u8; 3]) {
+    let (default_fg, default_bg) = st.default_colors();
+    let a = cell.attrs;
+    let (mut fg, mut bg) = (rgb(a.fg, default_fg, st), rgb(a.bg, default_bg, st));
+    if a.inverse {
+        (fg, bg) = (bg, fg);
+    }
+    if a.dim {
+        fg = mix(bg, fg, 8);
+    }
+    (fg, bg)
+}
+/// `a` blended towards `b` by `t` fifteenths.
+fn mix(a: [u8; 3], b: [u8; 3], t: u8) -> [u8; 3] {
+    let t = t.min(15) as i32;
+    [0, 1, 2].map(|i| (a[i] as i32 + (b[i] as i32 - a[i] as i32) * t / 15) as u8)
+}
+
+/// Draws `screen` one `font` cell per terminal cell.
+pub fn png(screen: &Screen, st: &TerminalData, font: &BitmapFont) -> RgbImage {
+    let (cols, rows) = screen.size();
+    let (w, h) = (font.width, font.height);
+    let mut img = RgbImage::new((cols * w) as u32, (rows * h) as u32);
+    for y in 0..rows {
+        for x in 0..cols {
+            let cell = screen.cell(x, y);
+            let (fg, bg) = cell_colors(cell, st);
+            let glyph = cell.text.chars().next().filter(|&c| c != ' ').and_then(|c| font.glyph(c));
+            for py in 0..h {
+                for px in 0..w {
+                    let mut ink = glyph.map_or(0, |g| g[py * w + px]);
+                    // bold is drawn twice, a pixel apart
+                    if cell.attrs.bold && px > 0 {
+                        ink = ink.max(glyph.map_or(0, |g| g[py * w + px - 1]));
+                    }
+                    if cell.attrs.underline && py == h - 2 {
+                        ink = 15;
+                    }
+                    img.put_pixel((x * w + px) as u32, (y * h + py) as u32, Rgb(mix(bg, fg, ink)));
+                }
+            }
+        }
+    }
+    img
+}
+
+fn hex(c: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// `screen` as an SVG the size of its PNG, with each row's text in spans of one style.
+pub fn svg(screen: &Screen, st: &TerminalData, font: &BitmapFont) -> String {
+    let (cols, rows) = screen.size();
+    let (w, h) = (font.width, font.height);
+    let (_, default_bg) = st.default_colors();
+    // the proportions of DejaVu Sans Mono, which the bundled font is drawn from
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" font-family=\"DejaVu Sans Mono, monospace\" font-size=\"{2:.1}\" xml:space=\"preserve\">\n<rect width=\"100%\" height=\"100%\" fill=\"{3}\"/>\n",
+        cols * w,
+        rows * h,
+        h as f64 * 0.82,
+        hex(default_bg)
+    );
+    for y in 0..rows {
+        let cells: Vec<(&Cell, [u8; 3], [u8; 3])> = (0..cols)
+            .map(|x| {
+                let cell = screen.cell(x, y);
+                let (fg, bg) = cell_colors(cell, st);
+                (cell, fg, bg)
+            })
+            .collect();
+        // backgrounds, as one rectangle per run of a colour
+        let mut x = 0;
+        while x < cols {
+            let bg = cells[x].2;
+            let run = cells[x..].iter().take_while(|c| c.2 == bg).count();
+            if bg != default_bg {
+                let _ = writeln!(
+                    out,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{h}\" fill=\"{}\"/>",
+                    x * w,
+                    y * h,
+                    run * w,
+                    hex(bg)
+                );
+            }
+            x += run;
+        }
+        // text, placed by column so wide and missing glyphs can't shift what follows
+        let mut spans = String::new();
+        let mut x = 0;
+        while x < cols {
+            let (cell, fg, _) = cells[x];
+            let style = (fg, cell.attrs.bold, cell.attrs.italic, cell.attrs.underline);
+            let run = cells[x..]
+                .iter()
+                .take_while(|c| (c.1, c.0.attrs.bold, c.0.attrs.italic, c.0.attrs.underline) == style)
+                .count();
+            let text: String = cells[x..x + run].iter().map(|c| c.0.text.as_str()).collect();
+            if !text.trim().is_empty() || style.3 {
+                let _ = write!(spans, "<tspan x=\"{}\" fill=\"{}\"", x * w, hex(fg));
+                if style.1 {
+                    spans.push_str(" font-weight=\"bold\"");
+                }
+                if style.2 {
+                    spans.push_str(" font-style=\"italic\"");
+                }
+                if style.3 {
+                    spans.push_str(" text-decoration=\"underline\"");
+                }
+                let _ = write!(spans, ">{}</tspan>", xml_escape(&text));
+            }
+            x += run;
+        }
+        if !spans.is_empty() {
+            let _ = writeln!(out, "<text y=\"{:.1}\">{spans}</text>", (y as f64 + 0.76) * h as f64);
+        }
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+/// `weather_ssh screenshot ...`, `args` starts after the subcommand.
+pub fn run(args: &[String]) -> ExitCode {
+    let mut cli = match Cli::parse(args, USAGE) {
+        Ok(cli) => cli,
+        Err(code) => return code,
+    };
+    let font = match load_font() {
+        Ok(font) => font,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let (app, seed) = (cli.run.app, cli.seed());
+    let path = cli.out.take().unwrap_or_else(|| PathBuf::from(format!("{app}-{seed}.png")));
+    let screen = match block_on_paused(cli.run.screen(cli.ticks())) {
+        Ok(screen) => screen,
+        Err(code) => return code,
+    };
+    let st = read_term_data();
+    let written = if path.extension().is_some_and(|e| e == "svg") {
+        fs::write(&path, svg(&screen, &st, &font)).map_err(|e| e.to_string())
+    } else {
+        png(&screen, &st, &font).save(&path).map_err(|e| e.to_string())
+    };
+    if let Err(e) = written {
+        eprintln!("could not write {}: {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+    eprintln!("wrote {} ({}s of {app}, seed {seed})", path.display(), cli.seconds);
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::is_narrow;
+    use crate::termdata::load_term_data;
+
+    #[test]
+    fn font_covers_the_glyph_table() {
+        let font = load_font().unwrap();
+        let st = read_term_data();
+        for &(c, _) in st.glyphs.iter().filter(|g| is_narrow(g.0)) {
+            assert!(font.has(c), "font.txt has no {c:?}");
+        }
+    }
+
+    #[test]
+    fn png_colours() {
+        let st = load_term_data(None).unwrap();
+        let font = load_font().unwrap();
+        let mut screen = Screen::new(3, 1);
+        screen.feed(b"\x1b[4;31;44m#\x1b[0m \x1b[48;5;196m ");
+        let img = png(&screen, &st, &font);
+        let (w, h) = (font.width as u32, font.height as u32);
+        assert_eq!(img.dimensions(), (3 * w, h));
+        let pixels = |x0: u32| (0..h).flat_map(move |y| (x0..x0 + w).map(move |x| (x, y)));
+        // the glyph's cell has the background, and the foreground where the underline is
+        assert!(pixels(0).any(|(x, y)| img.get_pixel(x, y).0 == st.palette[4]));
+        assert!(pixels(0).any(|(x, y)| img.get_pixel(x, y).0 == st.palette[1]));
+        let (_, bg) = st.default_colors();
+        assert!(pixels(w).all(|(x, y)| img.get_pixel(x, y).0 == bg));
+        assert!(pixels(2 * w).all(|(x, y)| img.get_pixel(x, y).0 == [255, 0, 0]));
+    }
+
+    #[test]
+    fn svg_spans() {
+        let st = load_term_data(None).unwrap();
+        let font = load_font().unwrap();
+        let mut screen = Screen::new(8, 2);
+        screen.feed(b"a<b\x1b[1;32mok\r\n\x1b[0;7m  ");
+        let svg = svg(&screen, &st, &font);
+        let (fg, _) = st.default_colors();
+        assert!(svg.contains(&format!("<tspan x=\"0\" fill=\"{}\">a&lt;b</tspan>", hex(fg))), "{svg}");
+        let green = format!("<tspan x=\"{}\" fill=\"{}\" font-weight=\"bold\">ok</tspan>", 3 * font.width, hex(st.palette[2]));
+        assert!(svg.contains(&green), "{svg}");
+        // inverse blanks show as a rectangle of the foreground colour
+        let rect = format!("<rect x=\"0\" y=\"{0}\" width=\"{1}\" height=\"{0}\" fill=\"{2}\"/>", font.height, 2 * font.width, hex(fg));
+        assert!(svg.contains(&rect), "{svg}");
+    }
+}
@@ -0,0 +1,69 @@
+//! Where randomness comes from. Apps get their RNGs from a seed rather than from the OS, so
+//! any scene can be replayed from the seed its session logged.
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::config::config;
+use crate::logging::civil_date;
+
+/// How seeds are picked when a session doesn't ask for one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SeedSource {
+    /// A fresh seed each time.
+    Random,
+    Fixed(u64),
+    /// The scene of the day: everyone gets the same one until midnight UTC.
+    Daily,
+}
+/// A number, `daily` or `random`.
+impl FromStr for SeedSource {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "random" => Ok(SeedSource::Random),
+            "daily" | "today" => Ok(SeedSource::Daily),
+            n => n.parse().map(SeedSource::Fixed).map_err(|_| ()),
+        }
+    }
+}
+impl SeedSource {
+    /// The seed this stands for right now, if it stands for one.
+    pub fn seed(self) -> Option<u64> {
+        match self {
+            SeedSource::Random => None,
+            SeedSource::Fixed(seed) => Some(seed),
+            SeedSource::Daily => Some(daily()),
+        }
+    }
+}
+
+/// Today's date (UTC) as YYYYMMDD, which doubles as a seed anyone can type back in.
+pub fn daily() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day) = civil_date((now.as_secs() / 86400) as i64);
+    (year * 10000 + month * 100 + day) as u64
+}
+
+/// The server's seed (`WEATHER_SSH_SEED`), if it has one.
+pub fn configured() -> Option<u64> {
+    config().seed.seed()
+}
+
+/// A seed for when none was asked for.
+pub fn random() -> u64 {
+    rand::rng().random()
+}
+
+/// The generator apps draw from. Unlike `SmallRng` its output is specified, so a seed gives
+/// the same scene on every platform and after every dependency update.
+pub type SeedRng = ChaCha8Rng;
+
+pub fn rng_from(seed: u64) -> SeedRng {
+    SeedRng::seed_from_u64(seed)
+}
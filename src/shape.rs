@@ -0,0 +1,173 @@
+use crate::frame::{Frame, FromRGB, ToAnsi};
+use crate::termdata::TerminalData;
+
+/// Picks a texel from the `grid`x`grid` sub-pixels (row-major) of one cell, so that
+/// e.g. a diagonal edge becomes `/` instead of whatever glyph has the same average ink.
+pub trait FromRGBShape<ST>: FromRGB<ST> + Sized {
+    /// Sub-cell grid size `st` has glyph shapes for, `None` if it can only match brightness.
+    fn grid(st: &ST) -> Option<usize>;
+    fn from_rgb_shape(pixels: &[[u8; 3]], st: &ST) -> (char, Self);
+}
+
+/// Side length of the sub-cell grid the glyph table was measured on, if it has one.
+pub fn shape_grid(st: &TerminalData) -> Option<usize> {
+    let n = st.shapes.first()?.1.len();
+    let g = (n as f32).sqrt().round() as usize;
+    (g > 1 && g * g == n).then_some(g)
+}
+
+fn luminance(p: [u8; 3]) -> f32 {
+    0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+}
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u32; 3];
+    for p in pixels {
+        for c in 0..3 {
+            sum[c] += p[c] as u32;
+        }
+    }
+    let n = pixels.len().max(1) as u32;
+    [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+}
+/// The glyph whose sub-cell ink is closest to `target` (already in ink units).
+fn best_glyph(target: &[f32], st: &TerminalData) -> char {
+    let mut best = (' ', f32::MAX);
+    for (c, cells) in &st.shapes {
+        let d: f32 = cells.iter().zip(target).map(|(a, b)| (a - b) * (a - b)).sum();
+        if d < best.1 {
+            best = (*c, d);
+        }
+    }
+    best.0
+}
+/// Luminance spread below which a cell is treated as flat and gets the plain `FromRGB` texel.
+const FLAT: f32 = 24.0;
+
+impl FromRGBShape<TerminalData> for () {
+    fn grid(st: &TerminalData) -> Option<usize> {
+        shape_grid(st)
+    }
+    fn from_rgb_shape(pixels: &[[u8; 3]], st: &TerminalData) -> (char, Self) {
+        if shape_grid(st).map(|g| g * g) != Some(pixels.len()) {
+            let [r, g, b] = average(pixels);
+            return <()>::from_rgb(r, g, b, st);
+        }
+        let max = st.glyphs.iter().map(|c| c.1).max().unwrap_or(1) as f32;
+        let (fg, bg) = st.default_colors();
+        let (lf, lb) = (luminance(fg), luminance(bg));
+        let target: Vec<f32> = pixels
+            .iter()
+            .map(|&p| if lf != lb { ((luminance(p) - lb) / (lf - lb)).clamp(0.0, 1.0) * max } else { 0.0 })
+            .collect();
+        (best_glyph(&target, st), ())
+    }
+}
+
+/// Glyph from the contrast-stretched pattern, then fg/bg from the pixels the glyph does and
+/// doesn't cover. Returns `None` for flat cells, which are better served by `FromRGB`.
+fn split_cell(pixels: &[[u8; 3]], st: &TerminalData) -> Option<(char, [u8; 3], [u8; 3])> {
+    if shape_grid(st).map(|g| g * g) != Some(pixels.len()) {
+        return None;
+    }
+    let lum: Vec<f32> = pixels.iter().map(|&p| luminance(p)).collect();
+    let lo = lum.iter().cloned().fold(f32::MAX, f32::min);
+    let hi = lum.iter().cloned().fold(f32::MIN, f32::max);
+    if hi - lo < FLAT {
+        return None;
+    }
+    let max = st.shapes.iter().flat_map(|s| s.1.iter()).cloned().fold(1.0, f32::max);
+    let target: Vec<f32> = lum.iter().map(|l| (l - lo) / (hi - lo) * max).collect();
+    let c = best_glyph(&target, st);
+    let cells = &st.shapes.iter().find(|s| s.0 == c)?.1;
+    let mut fg = [0.0f32; 3];
+    let mut bg = [0.0f32; 3];
+    let (mut fw, mut bw) = (0.0, 0.0);
+    for (p, ink) in pixels.iter().zip(cells) {
+        let w = (ink / max).clamp(0.0, 1.0);
+        for i in 0..3 {
+            fg[i] += p[i] as f32 * w;
+            bg[i] += p[i] as f32 * (1.0 - w);
+        }
+        fw += w;
+        bw += 1.0 - w;
+    }
+    if fw <= 0.0 || bw <= 0.0 {
+        return None;
+    }
+    Some((c, fg.map(|v| (v / fw) as u8), bg.map(|v| (v / bw) as u8)))
+}
+impl FromRGBShape<TerminalData> for u8 {
+    fn grid(st: &TerminalData) -> Option<usize> {
+        shape_grid(st)
+    }
+    fn from_rgb_shape(pixels: &[[u8; 3]], st: &TerminalData) -> (char, Self) {
+        let nearest = |rgb: [u8; 3]| {
+            (0..st.palette.len())
+                .min_by_key(|&i| {
+                    (0..3)
+                        .map(|c| (st.palette[i][c] as i32 - rgb[c] as i32).pow(2))
+                        .sum::<i32>()
+                })
+                .unwrap_or(0) as u8
+        };
+        match split_cell(pixels, st) {
+            Some((c, fg, bg)) => (c, (nearest(fg) << 4) + nearest(bg)),
+            None => {
+                let [r, g, b] = average(pixels);
+                u8::from_rgb(r, g, b, st)
+            }
+        }
+    }
+}
+impl FromRGBShape<TerminalData> for u16 {
+    fn grid(st: &TerminalData) -> Option<usize> {
+        shape_grid(st)
+    }
+    fn from_rgb_shape(pixels: &[[u8; 3]], st: &TerminalData) -> (char, Self) {
+        let cube = |rgb: [u8; 3]| {
+            let l = rgb.map(|v| ((v as f32) / 255.0 * 5.0).round() as u16);
+            l[0] * 36 + l[1] * 6 + l[2] + 16
+        };
+        match split_cell(pixels, st) {
+            Some((c, fg, bg)) => (c, (cube(fg) << 8) + cube(bg)),
+            None => {
+                let [r, g, b] = average(pixels);
+                u16::from_rgb(r, g, b, st)
+            }
+        }
+    }
+}
+
+impl<C> Frame<C>
+where
+    C: Clone + PartialEq + ToAnsi,
+{
+    /// Like `put_rgb`, but `pixels` holds `grid`x`grid` sub-pixels per texel
+    /// (so it is `w * grid` wide) and every texel is shape matched.
+    #[allow(clippy::too_many_arguments)]
+    pub fn put_rgb_shapes<ST>(
+        &mut self,
+        x0: usize,
+        y0: usize,
+        w: usize,
+        h: usize,
+        grid: usize,
+        pixels: &[[u8; 3]],
+        st: &ST,
+    ) where
+        C: FromRGBShape<ST>,
+    {
+        let stride = w * grid;
+        let mut cell = Vec::with_capacity(grid * grid);
+        for y in 0..h.min(self.height.saturating_sub(y0)) {
+            for x in 0..w.min(self.width.saturating_sub(x0)) {
+                cell.clear();
+                for sy in 0..grid {
+                    let row = (y * grid + sy) * stride + x * grid;
+                    cell.extend_from_slice(&pixels[row..row + grid]);
+                }
+                let _ = self.set_texel(x0 + x, y0 + y, C::from_rgb_shape(&cell, st));
+            }
+        }
+    }
+}
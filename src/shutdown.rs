@@ -0,0 +1,114 @@
+//! Graceful shutdown: on SIGINT/SIGTERM every open session gets its terminal restored, or
+//! for exec sessions an exit status, and its channel closed before the process exits.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use russh::{ChannelId, CryptoVec, server::Handle};
+use tokio::{
+    sync::RwLock,
+    task::{AbortHandle, JoinSet},
+    time::{sleep, timeout},
+};
+
+/// Undoes what the apps do to the terminal: attributes, hidden cursor and, if `exit_window`,
+/// the alternate screen.
+pub fn restore_sequence(exit_window: bool) -> String {
+    format!("\x1b[0m\x1b[?25h{}", if exit_window { "\x1b[?1049l" } else { "" })
+}
+
+/// A session channel that has to be cleaned up on shutdown.
+pub struct LiveSession {
+    pub handle: Handle,
+    pub channel: ChannelId,
+    pub exit_window: Arc<RwLock<bool>>,
+    /// The app writing to the channel, once one runs.
+    pub app: Option<AbortHandle>,
+    /// Whether the app draws on a terminal, so there is one to restore.
+    pub pty: bool,
+}
+
+/// What an exec session cut short by shutdown exits with, as a shell reports SIGTERM.
+const TERMINATED: u32 = 128 + 15;
+
+fn registry() -> &'static Mutex<HashMap<u64, LiveSession>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<u64, LiveSession>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Adds a session and returns the id to update or remove it with.
+pub fn register(session: LiveSession) -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    registry().lock().unwrap().insert(id, session);
+    id
+}
+pub fn set_app(id: u64, app: AbortHandle, pty: bool) {
+    if let Some(s) = registry().lock().unwrap().get_mut(&id) {
+        s.app = Some(app);
+        s.pty = pty;
+    }
+}
+pub fn unregister(id: u64) {
+    registry().lock().unwrap().remove(&id);
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Stops every app, restores every terminal, shows `goodbye` and closes every channel,
+/// giving up on clients that take longer than `limit` to accept that. Exec sessions get
+/// `goodbye` on stderr and an exit status instead, their output may be going to a file.
+pub async fn drain(goodbye: Option<&str>, limit: Duration) {
+    let sessions: Vec<LiveSession> = registry().lock().unwrap().drain().map(|(_, s)| s).collect();
+    let mut closing = JoinSet::new();
+    for s in sessions {
+        // the app could otherwise send another frame after the restore, or its own exit status
+        if let Some(app) = &s.app {
+            app.abort();
+        }
+        let goodbye = goodbye.map(str::to_string);
+        closing.spawn(async move {
+            if s.pty {
+                let mut out = restore_sequence(*s.exit_window.read().await);
+                if let Some(goodbye) = goodbye {
+                    out += &format!("{goodbye}\n\r");
+                }
+                let _ = s.handle.data(s.channel, CryptoVec::from(out)).await;
+            } else if s.app.is_some() {
+                if let Some(goodbye) = goodbye {
+                    let _ = s.handle.extended_data(s.channel, 1, CryptoVec::from(goodbye + "\n")).await;
+                }
+                let _ = s.handle.exit_status_request(s.channel, TERMINATED).await;
+                let _ = s.handle.eof(s.channel).await;
+            }
+            sleep(Duration::from_millis(1)).await;
+            let _ = s.handle.close(s.channel).await;
+        });
+    }
+    let _ = timeout(limit, closing.join_all()).await;
+}
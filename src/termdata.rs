@@ -0,0 +1,425 @@
+//! Glyph and palette tables used to turn RGB into texels, and the bitmap font screenshots
+//! are drawn with.
+//!
+//! The defaults are `lines.txt`, `colors.txt` and `font.txt` from the repository root,
+//! compiled into the binary; each file describes its format in its header comments. A file
+//! with the same name in the `WEATHER_SSH_DATA` directory replaces the built-in one.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+
+use crate::config::config;
+use crate::frame::{FULL_INK, is_narrow};
+use crate::lut::{self, LutKey, Luts};
+
+const DEFAULT_LINES: &str = include_str!("../lines.txt");
+const DEFAULT_COLORS: &str = include_str!("../colors.txt");
+const DEFAULT_FONT: &str = include_str!("../font.txt");
+
+/// The 16 ANSI colours as the terminal draws them.
+pub type Palette = Vec<[u8; 3]>;
+/// A glyph and its ink per sub-cell, row by row.
+pub type Shape = (char, Vec<f32>);
+/// What lines.txt holds: usable glyphs with their ink, the biggest ink, and glyph shapes.
+pub type GlyphTable = (Vec<(char, i32)>, i32, Vec<Shape>);
+
+#[derive(Clone)]
+pub struct TerminalData {
+    pub palette: Palette,
+    /// The terminal's default colours, used by texels without colour. White on black if unknown.
+    pub foreground: Option<[u8; 3]>,
+    pub background: Option<[u8; 3]>,
+    /// Every usable glyph and its ink.
+    pub glyphs: Vec<(char, i32)>,
+    pub biggest: i32,
+    /// Sub-cell ink of the glyphs that have it, all on the same grid (see `shape`).
+    pub shapes: Vec<Shape>,
+    /// Conversions cached for these tables, shared with all others like them. Call
+    /// `share_luts` after changing any of the above.
+    pub luts: Arc<Luts>,
+}
+
+impl TerminalData {
+    /// Default foreground and background colours.
+    pub fn default_colors(&self) -> ([u8; 3], [u8; 3]) {
+        (self.foreground.unwrap_or([255; 3]), self.background.unwrap_or([0; 3]))
+    }
+    /// Switches to the conversion tables for the current colours and glyphs, filled in by
+    /// whichever session first needs them.
+    pub fn share_luts(&mut self) {
+        let mut glyphs = DefaultHasher::new();
+        (&self.glyphs, self.biggest).hash(&mut glyphs);
+        for (c, ink) in &self.shapes {
+            c.hash(&mut glyphs);
+            ink.iter().for_each(|v| v.to_bits().hash(&mut glyphs));
+        }
+        self.luts = lut::shared(LutKey {
+            palette: self.palette.clone(),
+            foreground: self.foreground,
+            background: self.background,
+            glyphs: glyphs.finish(),
+        });
+    }
+}
+
+#[derive(Debug)]
+pub enum DataError {
+    Io(PathBuf, io::Error),
+    Parse {
+        file: String,
+        line: usize,
+        kind: ParseErrorKind,
+    },
+    UnknownPalette(String),
+}
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    MissingInk,
+    BadNumber(String),
+    BadColor(String),
+    /// A colour before the first `[name]` line.
+    NoPaletteName,
+    WrongColorCount {
+        palette: String,
+        found: usize,
+    },
+    NoGlyphs,
+    BadCellSize(String),
+    /// A glyph with the wrong number of pixels, or pixels that aren't hex digits.
+    BadBitmap { pixels: usize },
+}
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataError::Io(path, e) => write!(f, "could not read {}: {e}", path.display()),
+            DataError::Parse { file, line, kind } => write!(f, "{file}:{line}: {kind}"),
+            DataError::UnknownPalette(name) => write!(f, "there is no palette named \"{name}\""),
+        }
+    }
+}
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingInk => write!(f, "expected `c:ink`"),
+            ParseErrorKind::BadNumber(s) => write!(f, "\"{s}\" is not a number"),
+            ParseErrorKind::BadColor(s) => write!(
+                f,
+                "\"{s}\" is not a colour, use #rrggbb or a decimal 0xrrggbb"
+            ),
+            ParseErrorKind::NoPaletteName => write!(f, "colour outside of a [palette]"),
+            ParseErrorKind::WrongColorCount { palette, found } => {
+                write!(f, "palette \"{palette}\" has {found} colours instead of 16")
+            }
+            ParseErrorKind::NoGlyphs => write!(f, "no usable glyphs"),
+            ParseErrorKind::BadCellSize(s) => write!(f, "\"{s}\" is not a cell size, use WIDTHxHEIGHT"),
+            ParseErrorKind::BadBitmap { pixels } => write!(f, "expected `c:` and {pixels} hex digits"),
+        }
+    }
+}
+impl std::error::Error for DataError {}
+
+fn is_comment(line: &str) -> bool {
+    line.trim().is_empty() || line == "#" || line.starts_with("# ")
+}
+
+pub fn parse_glyphs(file: &str, data: &str) -> Result<GlyphTable, DataError> {
+    let err = |line: usize, kind| DataError::Parse {
+        file: file.to_string(),
+        line,
+        kind,
+    };
+    let mut glyphs = Vec::new();
+    let mut shapes: Vec<Shape> = Vec::new();
+    let mut biggest = FULL_INK;
+    for (i, line) in data.lines().enumerate() {
+        let n = i + 1;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if is_comment(line) {
+            continue;
+        }
+        let mut chars = line.chars();
+        let c = chars.next().unwrap();
+        let mut fields = chars
+            .as_str()
+            .strip_prefix(':')
+            .ok_or_else(|| err(n, ParseErrorKind::MissingInk))?
+            .split(':');
+        let ink_str = fields.next().unwrap().trim();
+        let ink = ink_str
+            .parse::<i32>()
+            .map_err(|_| err(n, ParseErrorKind::BadNumber(ink_str.to_string())))?;
+        biggest = biggest.max(ink);
+        // wide or ambiguous glyphs would shift the rest of the row
+        if !is_narrow(c) {
+            continue;
+        }
+        glyphs.push((c, ink));
+        if let Some(grid) = fields.next() {
+            let grid = grid
+                .split(',')
+                .map(|v| {
+                    v.trim()
+                        .parse::<f32>()
+                        .map_err(|_| err(n, ParseErrorKind::BadNumber(v.trim().to_string())))
+                })
+                .collect::<Result<Vec<f32>, _>>()?;
+            // rescale so the sub-cells agree with the (possibly hand-tuned) total
+            let mean = grid.iter().sum::<f32>() / grid.len() as f32;
+            let k = if mean > 0.0 { ink as f32 / mean } else { 0.0 };
+            shapes.push((c, grid.iter().map(|v| v * k).collect()));
+        }
+    }
+    if glyphs.is_empty() {
+        return Err(err(data.lines().count(), ParseErrorKind::NoGlyphs));
+    }
+    // shape matching needs every glyph measured on the same grid
+    let grid = shapes.first().map(|s| s.1.len()).unwrap_or(0);
+    shapes.retain(|s| s.1.len() == grid);
+    Ok((glyphs, biggest, shapes))
+}
+
+/// All palettes in `data`, in file order.
+pub fn parse_palettes(file: &str, data: &str) -> Result<Vec<(String, Palette)>, DataError> {
+    let err = |line: usize, kind| DataError::Parse {
+        file: file.to_string(),
+        line,
+        kind,
+    };
+    let mut palettes: Vec<(String, Palette, usize)> = Vec::new();
+    for (i, line) in data.lines().enumerate() {
+        let n = i + 1;
+        let line = line.trim();
+        if is_comment(line) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            palettes.push((name.trim().to_string(), Vec::new(), n));
+            continue;
+        }
+        let value = match line.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => u32::from_str_radix(hex, 16).ok(),
+            Some(_) => None,
+            None => line.parse::<u32>().ok().filter(|&v| v <= 0xffffff),
+        }
+        .ok_or_else(|| err(n, ParseErrorKind::BadColor(line.to_string())))?;
+        palettes
+            .last_mut()
+            .ok_or_else(|| err(n, ParseErrorKind::NoPaletteName))?
+            .1
+            .push([(value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+    palettes
+        .into_iter()
+        .map(|(name, colors, n)| {
+            if colors.len() == 16 {
+                Ok((name, colors))
+            } else {
+                Err(err(
+                    n,
+                    ParseErrorKind::WrongColorCount {
+                        palette: name,
+                        found: colors.len(),
+                    },
+                ))
+            }
+        })
+        .collect()
+}
+
+/// Glyph bitmaps for drawing screenshots.
+pub struct BitmapFont {
+    pub width: usize,
+    pub height: usize,
+    /// Coverage from 0 to 15 of each pixel, row by row.
+    glyphs: HashMap<char, Vec<u8>>,
+}
+impl BitmapFont {
+    pub fn has(&self, c: char) -> bool {
+        self.glyphs.contains_key(&c)
+    }
+    /// The pixels of `c`, or of `?` if the font doesn't have it.
+    pub fn glyph(&self, c: char) -> Option<&[u8]> {
+        self.glyphs.get(&c).or_else(|| self.glyphs.get(&'?')).map(Vec::as_slice)
+    }
+}
+
+pub fn parse_font(file: &str, data: &str) -> Result<BitmapFont, DataError> {
+    let err = |line: usize, kind| DataError::Parse {
+        file: file.to_string(),
+        line,
+        kind,
+    };
+    let mut lines = data
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.strip_suffix('\r').unwrap_or(l)))
+        .filter(|(_, l)| !is_comment(l));
+    let (n, size) = lines.next().ok_or_else(|| err(0, ParseErrorKind::NoGlyphs))?;
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|&(w, h): &(usize, usize)| w > 0 && h > 0)
+        .ok_or_else(|| err(n, ParseErrorKind::BadCellSize(size.to_string())))?;
+    let pixels = width * height;
+    let mut glyphs = HashMap::new();
+    for (n, line) in lines {
+        let mut chars = line.chars();
+        let c = chars.next().unwrap();
+        let bitmap: Option<Vec<u8>> = chars
+            .as_str()
+            .strip_prefix(':')
+            .filter(|p| p.len() == pixels)
+            .and_then(|p| p.chars().map(|d| d.to_digit(16).map(|d| d as u8)).collect());
+        glyphs.insert(c, bitmap.ok_or_else(|| err(n, ParseErrorKind::BadBitmap { pixels }))?);
+    }
+    if glyphs.is_empty() {
+        return Err(err(0, ParseErrorKind::NoGlyphs));
+    }
+    Ok(BitmapFont { width, height, glyphs })
+}
+pub fn load_font() -> Result<BitmapFont, DataError> {
+    let (file, data) = source("font.txt", DEFAULT_FONT)?;
+    parse_font(&file, &data)
+}
+
+/// Reads `name` from the override directory if it is there, otherwise the built-in copy.
+fn source(name: &str, default: &'static str) -> Result<(String, String), DataError> {
+    if let Some(dir) = &config().data_dir {
+        let path = dir.join(name);
+        match fs::read_to_string(&path) {
+            Ok(s) => return Ok((path.display().to_string(), s)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DataError::Io(path, e)),
+        }
+    }
+    Ok((format!("<built-in {name}>"), default.to_string()))
+}
+
+pub fn load_term_data(palette: Option<&str>) -> Result<TerminalData, DataError> {
+    let (file, data) = source("lines.txt", DEFAULT_LINES)?;
+    let (glyphs, biggest, shapes) = parse_glyphs(&file, &data)?;
+    let (file, data) = source("colors.txt", DEFAULT_COLORS)?;
+    let palettes = parse_palettes(&file, &data)?;
+    let palette = match palette {
+        Some(name) => palettes.into_iter().find(|p| p.0 == name),
+        None => palettes.into_iter().next(),
+    }
+    .ok_or_else(|| DataError::UnknownPalette(palette.unwrap_or_default().to_string()))?
+    .1;
+    let mut data = TerminalData {
+        palette,
+        foreground: None,
+        background: None,
+        glyphs,
+        biggest,
+        shapes,
+        luts: Arc::default(),
+    };
+    data.share_luts();
+    Ok(data)
+}
+
+static TERM_DATA: OnceLock<Arc<TerminalData>> = OnceLock::new();
+/// Loads the tables for the configured palette; call once at startup to report errors.
+pub fn init_term_data() -> Result<(), DataError> {
+    let data = load_term_data(config().palette.as_deref())?;
+    let _ = TERM_DATA.set(Arc::new(data));
+    Ok(())
+}
+/// The shared tables, loaded on first use if `init_term_data` wasn't called.
+pub fn read_term_data() -> Arc<TerminalData> {
+    TERM_DATA
+        .get_or_init(|| {
+            Arc::new(load_term_data(config().palette.as_deref()).unwrap_or_else(|e| panic!("{e}")))
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The line and kind of a parse error.
+    fn parse_error<T>(result: Result<T, DataError>) -> (usize, ParseErrorKind) {
+        match result {
+            Err(DataError::Parse { line, kind, .. }) => (line, kind),
+            Err(e) => panic!("not a parse error: {e}"),
+            Ok(_) => panic!("parsed"),
+        }
+    }
+
+    #[test]
+    fn glyphs() {
+        // `#:` and `::` are the glyphs # and :, only `#` and `# ...` are comments
+        let (glyphs, biggest, shapes) =
+            parse_glyphs("t", "# ink per glyph\n#\n\n#:7\n:: 3 \r\na:100000:1,3\nb:5").unwrap();
+        assert_eq!(glyphs, [('#', 7), (':', 3), ('a', 100000), ('b', 5)]);
+        assert_eq!(biggest, 100000);
+        // sub-cells are rescaled to the glyph's ink
+        assert_eq!(shapes, [('a', vec![50000.0, 150000.0])]);
+        // wide glyphs count towards the biggest ink but aren't used
+        let (glyphs, biggest, _) = parse_glyphs("t", " :0\n字:200000\n").unwrap();
+        assert_eq!((glyphs, biggest), (vec![(' ', 0)], 200000));
+    }
+
+    #[test]
+    fn glyph_errors() {
+        assert!(matches!(parse_error(parse_glyphs("t", "# x\na:1\nab")), (3, ParseErrorKind::MissingInk)));
+        assert!(matches!(parse_error(parse_glyphs("t", "a:x\n")), (1, ParseErrorKind::BadNumber(s)) if s == "x"));
+        let grid = parse_error(parse_glyphs("t", "a:1\nb:2:1,?"));
+        assert!(matches!(grid, (2, ParseErrorKind::BadNumber(s)) if s == "?"));
+        // reported at the last line, with or without a newline after it
+        assert!(matches!(parse_error(parse_glyphs("t", "# x\n字:5\n")), (2, ParseErrorKind::NoGlyphs)));
+        assert!(matches!(parse_error(parse_glyphs("t", "# x\n# y")), (2, ParseErrorKind::NoGlyphs)));
+    }
+
+    const SIXTEEN: &str = "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n#ffffff";
+
+    #[test]
+    fn palettes() {
+        let data = format!("# palettes\n[ one ]\n{SIXTEEN}\n\n[two]\n#\n{SIXTEEN}");
+        let palettes = parse_palettes("t", &data).unwrap();
+        assert_eq!(palettes.iter().map(|p| p.0.as_str()).collect::<Vec<_>>(), ["one", "two"]);
+        assert_eq!(palettes[1].1[2], [0, 0, 2]);
+        assert_eq!(palettes[1].1[15], [255, 255, 255]);
+    }
+
+    #[test]
+    fn palette_errors() {
+        let error = |data: &str| parse_error(parse_palettes("t", data));
+        assert!(matches!(error("[a]\n#fff"), (2, ParseErrorKind::BadColor(s)) if s == "#fff"));
+        assert!(matches!(error("[a]\n16777216"), (2, ParseErrorKind::BadColor(_))));
+        assert!(matches!(error("# x\n#ffffff\n[a]"), (2, ParseErrorKind::NoPaletteName)));
+        // reported at the palette's name
+        let (line, kind) = error(&format!("[a]\n{SIXTEEN}\n[b]\n1\n2"));
+        assert_eq!(line, 18);
+        assert!(matches!(kind, ParseErrorKind::WrongColorCount { palette, found: 2 } if palette == "b"));
+    }
+
+    #[test]
+    fn font() {
+        let font = parse_font("t", "# 2x1 cells\n2x1\n#:0f\n::f0\r\n?:88").unwrap();
+        assert_eq!((font.width, font.height), (2, 1));
+        assert_eq!(font.glyph('#'), Some(&[0, 15][..]));
+        assert_eq!(font.glyph(':'), Some(&[15, 0][..]));
+        // missing glyphs fall back to ?
+        assert_eq!(font.glyph('x'), Some(&[8, 8][..]));
+    }
+
+    #[test]
+    fn font_errors() {
+        let error = |data: &str| parse_error(parse_font("t", data));
+        assert!(matches!(error("# x\n2by1\na:00"), (2, ParseErrorKind::BadCellSize(s)) if s == "2by1"));
+        assert!(matches!(error("0x1\na:"), (1, ParseErrorKind::BadCellSize(_))));
+        assert!(matches!(error("2x1\na:00\nb:000"), (3, ParseErrorKind::BadBitmap { pixels: 2 })));
+        assert!(matches!(error("2x1\na:0g"), (2, ParseErrorKind::BadBitmap { .. })));
+        assert!(matches!(error("2x1\n# no glyphs"), (0, ParseErrorKind::NoGlyphs)));
+        assert!(matches!(error("# empty"), (0, ParseErrorKind::NoGlyphs)));
+    }
+}
@@ -0,0 +1,451 @@
+//! A model of the parts of a VT100/xterm the apps rely on: text, cursor movement, erasing,
+//! SGR colours (16, 256 and truecolor) and attributes, the alternate screen and cursor
+//! visibility. Fed the bytes a client would receive, it ends up with the grid of cells that
+//! client would show, for tests, recordings and screenshots.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::frame::char_width;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Ground,
+    Escape,
+    /// Inside `ESC [`, collecting parameters.
+    Csi,
+    /// Inside `ESC ]`, up to BEL or `ESC \`.
+    Osc,
+    OscEscape,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Color {
+    /// The terminal's own foreground or background.
+    #[default]
+    Default,
+    /// One of the 256 palette colours; the first 16 are the named ones.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// What SGR sets.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Attrs {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cell {
+    /// A grapheme cluster, or empty in the cell after a wide one.
+    pub text: String,
+    pub attrs: Attrs,
+}
+impl Cell {
+    /// An erased cell. Like xterm, erasing fills with the current background colour.
+    fn blank(attrs: Attrs) -> Self {
+        Cell {
+            text: " ".to_string(),
+            attrs: Attrs {
+                bg: attrs.bg,
+                ..Attrs::default()
+            },
+        }
+    }
+}
+
+/// A grid of cells fed with the bytes a client would receive.
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+    /// The main screen's cells and cursor while the alternate screen is shown.
+    saved: Option<(Vec<Cell>, (usize, usize))>,
+    x: usize,
+    y: usize,
+    /// Attributes for what's written next.
+    attrs: Attrs,
+    /// From `ESC 7` or `CSI s`.
+    saved_cursor: (usize, usize, Attrs),
+    /// Set after writing to the last column; the next char wraps first.
+    wrap_pending: bool,
+    cursor_visible: bool,
+    state: State,
+    params: String,
+    /// Bytes of a char split between two `feed` calls.
+    partial: Vec<u8>,
+}
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        Screen {
+            cols,
+            rows,
+            cells: vec![Cell::blank(Attrs::default()); cols * rows],
+            saved: None,
+            x: 0,
+            y: 0,
+            attrs: Attrs::default(),
+            saved_cursor: (0, 0, Attrs::default()),
+            wrap_pending: false,
+            cursor_visible: true,
+            state: State::Ground,
+            params: String::new(),
+            partial: Vec::new(),
+        }
+    }
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+    /// The cell at column `x` of row `y`.
+    pub fn cell(&self, x: usize, y: usize) -> &Cell {
+        &self.cells[y * self.cols + x]
+    }
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+    pub fn alternate(&self) -> bool {
+        self.saved.is_some()
+    }
+    /// The screen's text, one line per row without trailing blanks.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for row in self.cells.chunks(self.cols) {
+            let line: String = row.iter().map(|c| c.text.as_str()).collect();
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.partial.extend_from_slice(bytes);
+        let data = std::mem::take(&mut self.partial);
+        let (text, rest) = match std::str::from_utf8(&data) {
+            Ok(text) => (text, &[][..]),
+            Err(e) => {
+                let (valid, rest) = data.split_at(e.valid_up_to());
+                // an invalid sequence rather than a cut off one shows up as U+FFFD
+                match e.error_len() {
+                    Some(_) => {
+                        let lossy = String::from_utf8_lossy(&data).into_owned();
+                        self.feed_str(&lossy);
+                        return;
+                    }
+                    None => (std::str::from_utf8(valid).unwrap_or_default(), rest),
+                }
+            }
+        };
+        self.feed_str(text);
+        self.partial = rest.to_vec();
+    }
+    fn feed_str(&mut self, text: &str) {
+        for g in text.graphemes(true) {
+            // escape sequences and "\r\n" are handled a char at a time, text a cluster at a time
+            if self.state == State::Ground && !g.starts_with(char::is_control) {
+                self.print(g);
+            } else {
+                g.chars().for_each(|c| self.control(c));
+            }
+        }
+    }
+    fn control(&mut self, c: char) {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => self.state = State::Escape,
+                '\r' => self.carriage_return(),
+                '\n' => self.line_feed(),
+                '\x08' => {
+                    self.x = self.x.saturating_sub(1);
+                    self.wrap_pending = false;
+                }
+                _ => {}
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match c {
+                    '[' => {
+                        self.params.clear();
+                        self.state = State::Csi;
+                    }
+                    ']' => self.state = State::Osc,
+                    '7' => self.saved_cursor = (self.x, self.y, self.attrs),
+                    '8' => self.restore_cursor(),
+                    'M' => self.reverse_index(),
+                    _ => {}
+                }
+            }
+            State::Csi => {
+                if ('\x40'..='\x7e').contains(&c) {
+                    self.state = State::Ground;
+                    let params = std::mem::take(&mut self.params);
+                    self.csi(&params, c);
+                } else {
+                    self.params.push(c);
+                }
+            }
+            State::Osc => match c {
+                '\x07' => self.state = State::Ground,
+                '\x1b' => self.state = State::OscEscape,
+                _ => {}
+            },
+            State::OscEscape => self.state = if c == '\\' { State::Ground } else { State::Osc },
+        }
+    }
+    fn print(&mut self, g: &str) {
+        let width = g.chars().next().map_or(1, char_width).max(1);
+        if self.wrap_pending || self.x + width > self.cols {
+            self.carriage_return();
+            self.line_feed();
+        }
+        let i = self.y * self.cols + self.x;
+        self.cells[i] = Cell {
+            text: g.to_string(),
+            attrs: self.attrs,
+        };
+        if width == 2 && self.x + 1 < self.cols {
+            self.cells[i + 1] = Cell {
+                text: String::new(),
+                attrs: self.attrs,
+            };
+        }
+        self.x += width;
+        if self.x >= self.cols {
+            self.x = self.cols - 1;
+            self.wrap_pending = true;
+        }
+    }
+    fn carriage_return(&mut self) {
+        self.x = 0;
+        self.wrap_pending = false;
+    }
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.y + 1 < self.rows {
+            self.y += 1;
+        } else {
+            self.cells.drain(..self.cols);
+            self.cells.resize(self.cols * self.rows, Cell::blank(self.attrs));
+        }
+    }
+    /// Up a line, scrolling the screen down at the top.
+    fn reverse_index(&mut self) {
+        self.wrap_pending = false;
+        if self.y > 0 {
+            self.y -= 1;
+        } else {
+            self.cells.truncate(self.cols * (self.rows - 1));
+            let blank = vec![Cell::blank(self.attrs); self.cols];
+            self.cells.splice(0..0, blank);
+        }
+    }
+    fn restore_cursor(&mut self) {
+        let (x, y, attrs) = self.saved_cursor;
+        (self.x, self.y, self.attrs) = (x.min(self.cols - 1), y.min(self.rows - 1), attrs);
+        self.wrap_pending = false;
+    }
+    fn csi(&mut self, params: &str, command: char) {
+        let private = params.starts_with('?');
+        let nums: Vec<usize> = params
+            .trim_start_matches('?')
+            // colon separated sub-parameters (`38:5:n`) are read like the semicolon form
+            .split([';', ':'])
+            .map(|p| p.parse().unwrap_or(0))
+            .collect();
+        // missing and zero parameters both mean the default
+        let n = |i: usize, default: usize| nums.get(i).copied().filter(|&n| n != 0).unwrap_or(default);
+        self.wrap_pending = false;
+        match command {
+            'H' | 'f' => {
+                self.y = (n(0, 1) - 1).min(self.rows - 1);
+                self.x = (n(1, 1) - 1).min(self.cols - 1);
+            }
+            'd' => self.y = (n(0, 1) - 1).min(self.rows - 1),
+            'G' => self.x = (n(0, 1) - 1).min(self.cols - 1),
+            'A' => self.y = self.y.saturating_sub(n(0, 1)),
+            'B' => self.y = (self.y + n(0, 1)).min(self.rows - 1),
+            'C' => self.x = (self.x + n(0, 1)).min(self.cols - 1),
+            'D' => self.x = self.x.saturating_sub(n(0, 1)),
+            'E' => (self.x, self.y) = (0, (self.y + n(0, 1)).min(self.rows - 1)),
+            'F' => (self.x, self.y) = (0, self.y.saturating_sub(n(0, 1))),
+            'J' => {
+                let here = self.y * self.cols + self.x;
+                let range = match nums[0] {
+                    0 => here..self.cells.len(),
+                    1 => 0..here + 1,
+                    _ => 0..self.cells.len(),
+                };
+                self.erase(range);
+            }
+            'K' => {
+                let start = self.y * self.cols;
+                let here = start + self.x;
+                let range = match nums[0] {
+                    0 => here..start + self.cols,
+                    1 => start..here + 1,
+                    _ => start..start + self.cols,
+                };
+                self.erase(range);
+            }
+            'X' => {
+                let here = self.y * self.cols + self.x;
+                let end = (here + n(0, 1)).min((self.y + 1) * self.cols);
+                self.erase(here..end);
+            }
+            'm' => self.sgr(&nums),
+            's' if !private => self.saved_cursor = (self.x, self.y, self.attrs),
+            'u' if !private => self.restore_cursor(),
+            'h' | 'l' if private => {
+                let set = command == 'h';
+                for mode in &nums {
+                    match mode {
+                        25 => self.cursor_visible = set,
+                        1049 => self.alternate_screen(set),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    fn erase(&mut self, range: std::ops::Range<usize>) {
+        let blank = Cell::blank(self.attrs);
+        for cell in &mut self.cells[range] {
+            *cell = blank.clone();
+        }
+    }
+    fn sgr(&mut self, nums: &[usize]) {
+        let a = &mut self.attrs;
+        let mut nums = nums.iter().copied();
+        while let Some(n) = nums.next() {
+            match n {
+                0 => *a = Attrs::default(),
+                1 => a.bold = true,
+                2 => a.dim = true,
+                3 => a.italic = true,
+                4 => a.underline = true,
+                7 => a.inverse = true,
+                22 => (a.bold, a.dim) = (false, false),
+                23 => a.italic = false,
+                24 => a.underline = false,
+                27 => a.inverse = false,
+                30..=37 => a.fg = Color::Indexed((n - 30) as u8),
+                38 => a.fg = extended(&mut nums).unwrap_or(a.fg),
+                39 => a.fg = Color::Default,
+                40..=47 => a.bg = Color::Indexed((n - 40) as u8),
+                48 => a.bg = extended(&mut nums).unwrap_or(a.bg),
+                49 => a.bg = Color::Default,
+                90..=97 => a.fg = Color::Indexed((n - 90 + 8) as u8),
+                100..=107 => a.bg = Color::Indexed((n - 100 + 8) as u8),
+                _ => {}
+            }
+        }
+    }
+    fn alternate_screen(&mut self, on: bool) {
+        if on == self.alternate() {
+            return;
+        }
+        if on {
+            let blank = vec![Cell::blank(Attrs::default()); self.cols * self.rows];
+            self.saved = Some((std::mem::replace(&mut self.cells, blank), (self.x, self.y)));
+        } else if let Some((main, (x, y))) = self.saved.take() {
+            self.cells = main;
+            (self.x, self.y) = (x, y);
+        }
+    }
+}
+
+/// The colour after a 38 or 48: `5;n` or `2;r;g;b`.
+fn extended(nums: &mut impl Iterator<Item = usize>) -> Option<Color> {
+    let mut next = || nums.next().map(|n| n.min(255) as u8);
+    match next()? {
+        5 => Some(Color::Indexed(next()?)),
+        2 => Some(Color::Rgb(next()?, next()?, next()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+
+    #[test]
+    fn cursor_and_erase() {
+        let mut s = Screen::new(10, 3);
+        s.feed(b"hello\r\nworld\x1b[1;3Hy\x1b[2;2H\x1b[K");
+        assert_eq!(s.text(), "heylo\nw\n\n");
+        s.feed(b"\x1b[38;5;12mab\x1b[0m\x1b[3d\x1b[4Gc");
+        assert_eq!(s.text(), "heylo\nwab\n   c\n");
+    }
+
+    #[test]
+    fn wraps_and_scrolls() {
+        let mut s = Screen::new(4, 2);
+        s.feed("abcdefgh\u{e9}".as_bytes());
+        assert_eq!(s.text(), "efgh\n\u{e9}\n");
+        // a char cut in half between writes
+        let wide = "\u{4e16}".as_bytes();
+        s.feed(&wide[..1]);
+        s.feed(&wide[1..]);
+        assert_eq!(s.text(), "efgh\n\u{e9}\u{4e16}\n");
+        assert_eq!(s.cursor(), (3, 1));
+    }
+
+    #[test]
+    fn sgr() {
+        let mut s = Screen::new(6, 2);
+        s.feed(b"\x1b[1;31mA\x1b[22;38;5;200;48;2;1;2;3mB\x1b[0;94;101mC\x1b[39;7mD\x1b[m\x1b[44m\r\n\x1b[K");
+        let attrs: Vec<Attrs> = (0..4).map(|x| s.cell(x, 0).attrs).collect();
+        assert_eq!(attrs[0], Attrs { fg: Color::Indexed(1), bold: true, ..Attrs::default() });
+        assert_eq!(attrs[1], Attrs { fg: Color::Indexed(200), bg: Color::Rgb(1, 2, 3), ..Attrs::default() });
+        assert_eq!(attrs[2], Attrs { fg: Color::Indexed(12), bg: Color::Indexed(9), ..Attrs::default() });
+        assert_eq!(attrs[3], Attrs { bg: Color::Indexed(9), inverse: true, ..Attrs::default() });
+        // erasing fills with the background colour and nothing else
+        assert_eq!(s.cell(5, 1).attrs, Attrs { bg: Color::Indexed(4), ..Attrs::default() });
+        assert_eq!(s.cell(5, 0).attrs, Attrs::default());
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut f = Frame::new(7, 3, 0u16);
+        for y in 0..3 {
+            for x in 0..7 {
+                let c = (x * 40 + y * 3) as u16;
+                f.set_texel(x, y, (['·', '#', '@'][y], c << 8 | (255 - c))).unwrap();
+            }
+        }
+        f.set_texel(2, 1, ('\u{4e16}', 7)).unwrap();
+        let mut s = Screen::new(7, 3);
+        s.feed(f.render_str().as_bytes());
+        for y in 0..3 {
+            for x in 0..7 {
+                let cell = s.cell(x, y);
+                assert_eq!(cell.text, f.texel_str(x, y), "text at {x},{y}");
+                let c = f.texels[x + y * 7].1;
+                assert_eq!(
+                    (cell.attrs.fg, cell.attrs.bg),
+                    (Color::Indexed((c >> 8) as u8), Color::Indexed(c as u8)),
+                    "colour at {x},{y}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn alternate_screen() {
+        let mut s = Screen::new(5, 2);
+        s.feed(b"main\x1b[?1049h\x1b[?25l\x1b[Halt");
+        assert!(s.alternate() && !s.cursor_visible());
+        assert_eq!(s.text(), "alt\n\n");
+        s.feed(b"\x1b[?1049l\x1b[?25h");
+        assert_eq!(s.text(), "main\n\n");
+        assert_eq!(s.cursor(), (4, 0));
+    }
+}
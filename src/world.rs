@@ -0,0 +1,316 @@
+//! The weather simulation. Each scene runs once, in its own task, no matter how many sessions
+//! watch it; sessions subscribe to its state and only project and draw it at their own size.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use noise::{NoiseFn, Simplex};
+use rand::Rng;
+use tokio::{
+    sync::watch,
+    time::{MissedTickBehavior, interval},
+};
+
+use crate::logging::{self, Level};
+use crate::raster::Camera;
+use crate::scheduler::record_cpu;
+use crate::seed::{self, SeedRng};
+use crate::vec3::Vec3;
+
+/// Time between simulation steps, and so between frames.
+pub const TICK: Duration = Duration::from_millis(30);
+/// Height of the ground plane below the camera.
+pub const GROUND_Y: f64 = 5.0;
+/// Widest screen (width / height) the simulated volume covers; wider viewers see empty edges.
+const MAX_ASPECT: f64 = 6.0;
+/// Snowflakes spawned per unit of spawn area and time.
+const SNOW_DENSITY: f64 = 0.004;
+const SEE_DISTANCE: f64 = 10.0;
+/// Simulated time per step.
+const TMULT: f64 = 5.0;
+const WIND_X: f64 = 0.02;
+const WIND_Z: f64 = -0.02;
+/// Steps simulated before reporting on a scene nobody is watching, enough for the snow to
+/// fill the volume.
+const WARMUP_STEPS: usize = 200;
+
+/// What makes scenes differ. Sessions asking for the same one share its simulation.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Scene {
+    /// Multiplies how much snow falls.
+    pub intensity: f64,
+    /// Fixes the landscape, the wind and the snow; a fresh random seed if `None`.
+    pub seed: Option<u64>,
+}
+impl Default for Scene {
+    fn default() -> Self {
+        Scene { intensity: 1.0, seed: None }
+    }
+}
+impl Scene {
+    /// Key in the registry of running scenes.
+    fn key(&self) -> String {
+        let mut key = "weather".to_string();
+        if self.intensity != 1.0 {
+            key += &format!(" intensity={}", self.intensity);
+        }
+        if let Some(seed) = self.seed {
+            key += &format!(" seed={seed}");
+        }
+        key
+    }
+}
+
+/// One moment of a scene.
+pub struct WorldState {
+    /// What the scene was started from, so it can be started again.
+    pub seed: u64,
+    pub t: f64,
+    pub particles: Vec<Vec3>,
+    /// Shapes the ground and the wind.
+    pub noise: Simplex,
+}
+
+struct Triangle {
+    p: [Vec3; 3],
+}
+impl Triangle {
+    pub fn area(&self) -> f64 {
+        (self.p[0] - self.p[1]).cross(&(self.p[0] - self.p[2])).len()
+    }
+    pub fn random_point(&self, rng: &mut SeedRng) -> Vec3 {
+        let mut xn = rng.random();
+        let mut yn = rng.random();
+        if xn + yn > 1.0 {
+            xn = 0.5 - xn;
+            yn = 0.5 - yn;
+        }
+        self.p[0] + (self.p[1] - self.p[0]) * xn + (self.p[2] - self.p[0]) * yn
+    }
+    pub fn normal(&self) -> Vec3 {
+        (self.p[0] - self.p[1]).cross(&(self.p[0] - self.p[2])).normalize()
+    }
+}
+
+/*
+puddles
+snow/rain drops/flakes
+calculate average movement from wind+gravity
+calculate camera edge planes
+for each camera edge plane:
+calculate area
+the more area, the more random points are checked and have a chance to generate a snowflake if the wind's strong enough.
+area * normal dot movement = amount of snowflakes to spawn in this area
+wind
+ripples
+grass?
+sun
+clouds
+*/
+struct World {
+    seed: u64,
+    t: f64,
+    particles: Vec<Vec3>,
+    noise: Simplex,
+    rng: SeedRng,
+    density: f64,
+    /// Surfaces of the visible volume that snow blows in through.
+    spawners: Vec<Triangle>,
+}
+impl World {
+    fn new(scene: &Scene) -> World {
+        let seed = scene.seed.unwrap_or_else(seed::random);
+        World {
+            seed,
+            t: 0.0,
+            particles: vec![Vec3::new(0.0, -1.0, 1.0)],
+            // the landscape and the wind come from the noise, the snowflakes from the rng
+            noise: Simplex::new(seed as u32 ^ (seed >> 32) as u32),
+            rng: seed::rng_from(seed),
+            density: SNOW_DENSITY * scene.intensity,
+            spawners: spawners(),
+        }
+    }
+    fn step(&mut self) {
+        self.t += TMULT;
+        let (noise, t) = (self.noise, self.t);
+        for tr in &self.spawners {
+            let tries = self.density * tr.area() * TMULT;
+            let prob_plus_1 = tries % 1.0;
+            let tries = if self.rng.random::<f64>() < prob_plus_1 {
+                tries.ceil() as usize
+            } else {
+                tries.floor() as usize
+            };
+            let normal = tr.normal();
+            for _ in 0..tries {
+                let point = tr.random_point(&mut self.rng);
+                let wind = get_wind(noise, t, &point);
+                let prob = wind.dot(&normal);
+                if self.rng.random::<f64>() < prob {
+                    self.particles.push(point);
+                }
+            }
+        }
+        self.particles.retain_mut(|p| {
+            p.c[0] += WIND_X * TMULT;
+            p.c[1] += 0.03 * TMULT;
+            p.c[2] += WIND_Z * TMULT;
+            let curl = get_wind(noise, t, p);
+            *p = *p + curl * (TMULT / 100.0);
+            p.c[1] < GROUND_Y && p.c[2] > 0.0
+        });
+    }
+    fn state(&self) -> WorldState {
+        WorldState {
+            seed: self.seed,
+            t: self.t,
+            particles: self.particles.clone(),
+            noise: self.noise,
+        }
+    }
+}
+
+/// The sides of the volume seen by the widest supported camera.
+fn spawners() -> Vec<Triangle> {
+    let cam = Camera::new((MAX_ASPECT * 100.0) as usize, 100);
+    let cam_origin = cam.origin;
+    let y3d = GROUND_Y;
+    let camplanez = cam.top_left.c[2];
+    let camdir_top_left = cam.top_left;
+    let camdir_top_right = cam.top_left + cam.right;
+    let camdir_bottom_left = cam.top_left + cam.down;
+    let camdir_bottom_right = cam.top_left + cam.right + cam.down;
+    let cam_top_left = camdir_top_left / camplanez * SEE_DISTANCE;
+    let cam_top_right = camdir_top_right / camplanez * SEE_DISTANCE;
+    let mut cam_bottom_left_far = camdir_bottom_left / camplanez * SEE_DISTANCE;
+    cam_bottom_left_far.c[1] = y3d;
+    let mut cam_bottom_right_far = camdir_bottom_right / camplanez * SEE_DISTANCE;
+    cam_bottom_right_far.c[1] = y3d;
+    let cam_bottom_left_near = Vec3::new(
+        camdir_bottom_left.c[0] * y3d / camdir_bottom_left.c[2],
+        y3d,
+        camdir_bottom_left.c[1] * y3d / camdir_bottom_left.c[2],
+    );
+    let cam_bottom_right_near = Vec3::new(
+        camdir_bottom_right.c[0] * y3d / camdir_bottom_right.c[2],
+        y3d,
+        camdir_bottom_right.c[1] * y3d / camdir_bottom_right.c[2],
+    );
+    vec![
+        // top
+        Triangle { p: [cam_origin, cam_top_left, cam_top_right] },
+        // bottom
+        Triangle { p: [cam_origin, cam_bottom_right_near, cam_bottom_left_near] },
+        // left near
+        Triangle { p: [cam_origin, cam_bottom_left_near, cam_top_left] },
+        // left far
+        Triangle { p: [cam_bottom_left_near, cam_bottom_left_far, cam_top_left] },
+        // right near
+        Triangle { p: [cam_origin, cam_top_right, cam_bottom_right_near] },
+        // right far
+        Triangle { p: [cam_bottom_right_near, cam_top_right, cam_bottom_right_far] },
+        // far top left
+        Triangle { p: [cam_top_left, cam_bottom_left_far, cam_top_right] },
+        // far bottom right
+        Triangle { p: [cam_bottom_right_far, cam_top_right, cam_bottom_left_far] },
+    ]
+}
+
+/// Where reports measure the wind: at eye level, a little way in front of the camera.
+fn station() -> Vec3 {
+    Vec3::new(0.0, 0.0, 5.0)
+}
+/// Horizontal wind at the station at simulated time `t`, in scene units (about metres) per second.
+/// x is east, z is north.
+pub fn wind_at(noise: Simplex, t: f64) -> (f64, f64) {
+    let curl = get_wind(noise, t, &station());
+    let per_step = TMULT / TICK.as_secs_f64();
+    ((WIND_X + curl.c[0] / 100.0) * per_step, (WIND_Z + curl.c[2] / 100.0) * per_step)
+}
+/// Simulated time that passes in `d` of real time.
+pub fn sim_time(d: Duration) -> f64 {
+    d.as_secs_f64() / TICK.as_secs_f64() * TMULT
+}
+
+fn get_wind(noise: Simplex, t: f64, p: &Vec3) -> Vec3 {
+    curl_noise_3d_t(&noise, (p.c[0] - WIND_X * t) / 10.0, p.c[1] / 10.0, (p.c[2] - WIND_Z * t) / 10.0, t * 0.003)
+}
+pub fn curl_noise_3d_t(noise:&Simplex, x:f64, y:f64, z:f64, t:f64)->Vec3{
+    const D:f64=0.001;
+    Vec3::new(
+        (noise.get([x, y+D, z, t+2000.0])-noise.get([x, y-D, z, t+2000.0]))/2.0/D-(noise.get([x, y, z+D, t+1000.0])-noise.get([x, y, z-D, t+1000.0]))/2.0/D,
+        (noise.get([x, y, z+D, t])-noise.get([x, y, z-D, t]))/2.0/D-(noise.get([x+D, y, z, t+2000.0])-noise.get([x-D, y, z, t+2000.0]))/2.0/D,
+        (noise.get([x+D, y, z, t+1000.0])-noise.get([x-D, y, z, t+1000.0]))/2.0/D-(noise.get([x, y+D, z, t])-noise.get([x, y-D, z, t]))/2.0/D,
+    )
+}
+
+/// Running scenes by key. Holds a receiver of each so new viewers can subscribe.
+fn scenes() -> &'static Mutex<HashMap<String, watch::Receiver<Arc<WorldState>>>> {
+    static SCENES: OnceLock<Mutex<HashMap<String, watch::Receiver<Arc<WorldState>>>>> = OnceLock::new();
+    SCENES.get_or_init(Default::default)
+}
+
+/// Watches `scene`, starting its simulation if nobody else is watching it.
+pub fn subscribe(scene: &Scene) -> watch::Receiver<Arc<WorldState>> {
+    let key = scene.key();
+    let mut scenes = scenes().lock().unwrap();
+    // a scene whose task went away with its runtime, as in tests, is started over
+    if let Some(rx) = scenes.get(&key)
+        && rx.has_changed().is_ok()
+    {
+        return rx.clone();
+    }
+    let world = World::new(scene);
+    logging::event(Level::Info, "scene started", &[("scene", key.as_str().into()), ("seed", world.seed.into())]);
+    let (tx, rx) = watch::channel(Arc::new(world.state()));
+    scenes.insert(key.clone(), rx.clone());
+    tokio::spawn(run(key, world, tx));
+    rx
+}
+
+/// The latest state of `scene`. If it isn't running, a fresh one is simulated for a moment
+/// instead, without starting it.
+pub async fn snapshot(scene: &Scene) -> Arc<WorldState> {
+    if let Some(rx) = scenes().lock().unwrap().get(&scene.key()) {
+        return rx.borrow().clone();
+    }
+    let scene = *scene;
+    let warm_up = move || {
+        let started = std::time::Instant::now();
+        let mut world = World::new(&scene);
+        for _ in 0..WARMUP_STEPS {
+            world.step();
+        }
+        record_cpu(started.elapsed());
+        Arc::new(world.state())
+    };
+    match tokio::task::spawn_blocking(warm_up).await {
+        Ok(state) => state,
+        Err(_) => Arc::new(World::new(&scene).state()),
+    }
+}
+
+/// Steps `world` until only the registry is left watching it.
+async fn run(name: String, mut world: World, tx: watch::Sender<Arc<WorldState>>) {
+    let mut ticks = interval(TICK);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        {
+            // checked under the registry lock so a concurrent subscribe can't get a dead scene
+            let mut scenes = scenes().lock().unwrap();
+            if tx.receiver_count() <= 1 {
+                scenes.remove(&name);
+                return;
+            }
+        }
+        let started = std::time::Instant::now();
+        world.step();
+        record_cpu(started.elapsed());
+        tx.send_replace(Arc::new(world.state()));
+    }
+}